@@ -0,0 +1,154 @@
+//! `cargo bench`: runs some long programs one step at a time and from the block cache, and prints how many
+//! million instructions per second each manages. `cargo bench --features jit` adds a column for native code
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use avc_asm::assembler::assemble_with_isa;
+use avc_asm::isa::Isa;
+use avc_asm::processor::{Processor, ProcessorBuilder};
+
+/// the sieve of eratosthenes up to 255, COUNT2 * 256 times
+const SIEVE: &str = "
+REPEAT: lda #0
+        tax
+CLEAR:  lda #1
+        sta 0x1000,x
+        inc
+        txa
+        jnz CLEAR
+        lda #2
+        sta I
+OUTER:  lda I
+        tax
+        lda 0x1000,x
+        jez NEXT
+        lda I
+        swp
+        lda I
+        clc
+        add
+        jcs NEXT
+INNER:  tax
+        lda #0
+        sta 0x1000,x
+        txa
+        swp
+        lda I
+        swp
+        clc
+        add
+        jcc INNER
+NEXT:   lda I
+        tax
+        inc
+        txa
+        sta I
+        swp
+        lda #16
+        swp
+        cmp
+        jnz OUTER
+        lda COUNT
+        tax
+        dec
+        txa
+        sta COUNT
+        jnz REPEAT
+        lda COUNT2
+        tax
+        dec
+        txa
+        sta COUNT2
+        jnz REPEAT
+        hlt
+I:      dat 0
+COUNT:  dat 0
+COUNT2: dat 4";
+
+/// divides every number from 255 down to 1 by 7 by repeated subtraction, COUNT2 * 256 times
+const DIVISION: &str = "
+REPEAT: lda #255
+        sta N
+DIVIDE: lda #7
+        swp
+        lda N
+SUBL:   cmp
+        jcc DONE
+        sub
+        inc
+        jmp SUBL
+DONE:   lda #1
+        swp
+        lda N
+        sub
+        sta N
+        jnz DIVIDE
+        lda COUNT
+        tax
+        dec
+        txa
+        sta COUNT
+        jnz REPEAT
+        lda COUNT2
+        tax
+        dec
+        txa
+        sta COUNT2
+        jnz REPEAT
+        hlt
+N:      dat 0
+COUNT:  dat 0
+COUNT2: dat 4";
+
+fn processor(prog: &[u8]) -> Processor {
+    ProcessorBuilder::new().memory(prog).isa(Isa::V2).output(io::sink()).input(io::empty()).build()
+}
+
+/// returns the instructions executed and how long it took
+fn stepped(prog: &[u8]) -> (u64, Duration) {
+    let mut p = processor(prog);
+    let mut instructions = 0;
+    let start = Instant::now();
+    while !p.halted {
+        p.step().unwrap();
+        instructions += 1
+    }
+    (instructions, start.elapsed())
+}
+
+fn cached(prog: &[u8], jit: bool) -> Duration {
+    let mut p = processor(prog);
+    #[cfg(feature = "jit")]
+    {
+        p.jit = jit
+    }
+    #[cfg(not(feature = "jit"))]
+    let _ = jit;
+    let start = Instant::now();
+    while !p.halted {
+        p.run_fast().unwrap();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let jit = cfg!(feature = "jit");
+    print!("{:<10} {:>14} {:>12} {:>12}", "program", "instructions", "step MIPS", "cached MIPS");
+    if jit {
+        print!(" {:>12} {:>8}", "jit MIPS", "speedup")
+    }
+    println!();
+    for (name, src) in [("sieve", SIEVE), ("division", DIVISION)] {
+        let (prog, _) = assemble_with_isa(src, Isa::V2).unwrap();
+        let (instructions, step_time) = stepped(&prog);
+        let cached_time = cached(&prog, false);
+        let mips = |t: Duration| instructions as f64 / t.as_secs_f64() / 1e6;
+        print!("{:<10} {:>14} {:>12.1} {:>12.1}", name, instructions, mips(step_time), mips(cached_time));
+        if jit { // the speedup is over stepping
+            let jit_time = cached(&prog, true);
+            print!(" {:>12.1} {:>7.1}x", mips(jit_time), step_time.as_secs_f64() / jit_time.as_secs_f64())
+        }
+        println!()
+    }
+}
@@ -3,6 +3,8 @@ use std::collections::HashMap;
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 
+use crate::condition::Condition;
+use crate::isa::Isa;
 use crate::utils::{u16_to_bytes, parse_int_literal, set_vec_value_at_index, strip_whitespace};
 
 // parse program into Lines
@@ -14,11 +16,85 @@ struct Assembler {
     pub labels: HashMap<String, usize>,
     counter: usize,
     pub constants: HashMap<String, u8>,
-    macros: HashMap<String, Vec<String>>
+    macros: HashMap<String, Vec<String>>,
+    annotations: Vec<RawAnnotation>,
+    line_map: Vec<(usize, usize)>,
+    instruction_addresses: Vec<usize>,
+    segments: Vec<(usize, usize)>,
+    entry: Option<(String, usize)>, // the operand of the `entry` directive and its line
+    stack: Option<(String, usize)>,
+    isa: Isa
 }
 
-pub fn assemble(program: &str) -> Result<Box<[u8]>, String> {
-    let mut a = Assembler::default();
+/// everything the emulator needs to talk about a program in terms of its source
+#[derive(Default)]
+pub struct DebugInfo {
+    pub labels: HashMap<String, usize>,
+    pub lines: Vec<(usize, usize)>, // (address, source line), in address order
+    pub instructions: Vec<usize>, // the addresses of instructions, as opposed to data
+    pub annotations: Vec<Annotation>,
+    pub entry: Option<u16>, // from `entry`, if there is one
+    pub stack_pointer: Option<u16>, // from `stack`
+    pub segments: Vec<(usize, usize)> // (start, end) of the runs of bytes the program placed, in order, end exclusive
+}
+impl DebugInfo {
+    /// the source line of the instruction or data covering `addr`
+    pub fn line_at(&self, addr: usize) -> Option<usize> {
+        let i = self.lines.partition_point(|(a, _)| *a <= addr);
+        if i == 0 {
+            None
+        }
+        else {
+            Some(self.lines[i - 1].1)
+        }
+    }
+    /// names an address after the closest label at or before it, like `PRINT+3`
+    pub fn symbolize(&self, addr: usize) -> Option<String> {
+        let (label, at) = closest_label(&self.labels, addr)?;
+        if at == addr {
+            Some(label.clone())
+        }
+        else {
+            Some(format!("{}+{}", label, addr - at))
+        }
+    }
+}
+
+/// the closest label at or before `addr` and where it is. of several labels at one address, the first
+/// alphabetically wins, so the choice doesn't depend on hash order
+pub fn closest_label(labels: &HashMap<String, usize>, addr: usize) -> Option<(&String, usize)> {
+    labels.iter()
+        .filter(|(_, a)| **a <= addr)
+        .max_by_key(|(l, a)| (**a, std::cmp::Reverse(*l)))
+        .map(|(l, a)| (l, *a))
+}
+
+/// test metadata from `test`, `expect_out` and `assert` directives. these don't produce any bytes
+pub struct Annotation {
+    pub address: usize,
+    pub line: usize,
+    pub kind: AnnotationKind
+}
+pub enum AnnotationKind {
+    Test(String),
+    ExpectOut(Vec<u8>),
+    Assert(Condition)
+}
+struct RawAnnotation { // asserts can reference labels that aren't defined yet
+    address: usize,
+    line: usize,
+    instr: String,
+    op: String
+}
+
+/// assembles for the default isa, v1
+pub fn assemble_with_debug(program: &str) -> Result<(Box<[u8]>, DebugInfo), String> {
+    assemble_with_isa(program, Isa::default())
+}
+/// instructions that aren't in `isa` are errors
+#[allow(clippy::while_let_loop, clippy::single_match)]
+pub fn assemble_with_isa(program: &str, isa: Isa) -> Result<(Box<[u8]>, DebugInfo), String> {
+    let mut a = Assembler { isa, ..Assembler::default() };
 
     let mut lines = program.split('\n').peekable();
     let mut i = 1;
@@ -58,22 +134,31 @@ pub fn assemble(program: &str) -> Result<Box<[u8]>, String> {
                 }
             }
             Err(e) => {
-                return Err(format!("error on line {}: {}", i + 1, e))
+                return Err(format!("error on line {}: {}", i, e))
             }
         }
+        i += 1;
     }
     
-    match a.compile() { // PASS 3: compiling
-        Ok(bytes) => {
-            for label in a.labels.keys() {
-                println!("label {}, pointing to {} at {}", label, bytes[a.labels[label]], a.labels[label])
-            }
-            Ok(bytes)
-        }
-        Err(e) => Err(format!("error compiling: {}", e))
-    }
+    let bytes = match a.compile() { // PASS 3: compiling
+        Ok(bytes) => bytes,
+        Err(e) => return Err(format!("error compiling: {}", e))
+    };
+    let annotations = a.resolve_annotations()?;
+    let entry = a.resolve_address(&a.entry)?;
+    let stack_pointer = a.resolve_address(&a.stack)?;
+    Ok((bytes, DebugInfo {
+        labels: a.labels,
+        lines: a.line_map,
+        instructions: a.instruction_addresses,
+        annotations,
+        entry,
+        stack_pointer,
+        segments: a.segments
+    }))
 }
 impl Assembler {
+    #[allow(clippy::while_let_loop, clippy::useless_format)]
     pub fn process_declares(&mut self, decs: Vec<String>) -> Result<(), String> {
         let mut declarations = decs.iter();
 
@@ -127,6 +212,7 @@ impl Assembler {
         Ok(())
     }
 
+    #[allow(clippy::comparison_to_empty, clippy::len_zero, clippy::useless_format)]
     pub fn read_line(&mut self, s: &str, index: usize) -> Result<Option<Line>, String> { // index is line number. this is the worst way of doing it, i know
         let s = s.trim();
         if s == "" { // ignore empty strings
@@ -156,6 +242,31 @@ impl Assembler {
             self.expand_macro(main_instr, index)?;
             return Ok(None)
         }
+        let (directive, annotation_op) = main_instr.split_once(' ').unwrap_or((main_instr, ""));
+        if let "test"|"expect_out"|"assert" = directive { // annotations don't take up space
+            if annotation_op.trim().is_empty() {
+                return Err(format!("{} requires op, found none", directive))
+            }
+            self.annotations.push(RawAnnotation {
+                address: self.counter,
+                line: index,
+                instr: String::from(directive),
+                op: String::from(annotation_op.trim())
+            });
+            return Ok(None)
+        }
+        if let "entry"|"start"|"stack" = directive { // neither do these
+            let op = annotation_op.trim();
+            if op.is_empty() {
+                return Err(format!("{} requires op, found none", directive))
+            }
+            let slot = if directive == "stack" { &mut self.stack } else { &mut self.entry };
+            if let Some((_, line)) = slot {
+                return Err(format!("{} was already given on line {}", directive, line))
+            }
+            *slot = Some((String::from(op), index));
+            return Ok(None)
+        }
         
         self.counter += 1; // bump counter
 
@@ -180,7 +291,7 @@ impl Assembler {
         let mut line = Line {
             instruction: I::Nop,
             operand: Op::None,
-            program_text_line: index + 1
+            program_text_line: index
         };
         match instr {
             "nop" => line.instruction = I::Nop,
@@ -193,8 +304,8 @@ impl Assembler {
             "dec" => line.instruction = I::Dec,
             "add" => line.instruction = I::Add,
             //"adc" => line.instruction = I::Adc,
-            //"sub" => line.instruction = I::Sub,
-            //"sbc" => line.instruction = I::Sbc,
+            "sub" => line.instruction = I::Sub,
+            "sbc" => line.instruction = I::Sbc,
             "lsr" => line.instruction = I::Lsr,
             "lsl" => line.instruction = I::Lsl,
             "clc" => line.instruction = I::Clc,
@@ -202,20 +313,24 @@ impl Assembler {
             "put" => line.instruction = I::Put,
             "psa" => line.instruction = I::Psa,
             "ppa" => line.instruction = I::Ppa,
-            //"sst" => line.instruction = I::Sst,
-            //"gst" => line.instruction = I::Sst,
+            "gst" => line.instruction = I::Gst,
+            "sst" => line.instruction = I::Sst,
             "ssp" => line.instruction = I::Ssp,
             "gsp" => line.instruction = I::Gsp,
             "brk" => line.instruction = I::Brk,
             "rts" => line.instruction = I::Rts,
             "get" => line.instruction = I::Get,
             "gbf" => line.instruction = I::Gbf,
+            "sei" => line.instruction = I::Sei,
+            "cli" => line.instruction = I::Cli,
+            "rti" => line.instruction = I::Rti,
+            "cmp" => line.instruction = I::Cmp,
             "not" => line.instruction = I::Not,
             "and" => line.instruction = I::And,
             "ior" => line.instruction = I::Ior,
             "xor" => line.instruction = I::Xor,
             
-            "lda"|"sta"|"org"|"dat"|"jmp"|"jsr"|"jez"|"jgt" => { // jgz is gone :crab: :crab:
+            "lda"|"sta"|"org"|"dat"|"jmp"|"jsr"|"jez"|"jgt"|"jnz"|"jcs"|"jcc"|"jmi"|"jpl"|"jvs"|"jvc" => { // jgz is gone :crab: :crab:
                 if op == "" { // check operand exists
                     return Err(format!("instr {} requires op, found none", instr))
                 }
@@ -277,6 +392,13 @@ impl Assembler {
                                 "jsr" => 0b011,
                                 "jez" => 0b100,
                                 "jgt" => 0b101,
+                                "jnz" => 0b10_0000,
+                                "jcs" => 0b10_0001,
+                                "jcc" => 0b10_0010,
+                                "jmi" => 0b10_0011,
+                                "jpl" => 0b10_0100,
+                                "jvs" => 0b10_0101,
+                                "jvc" => 0b10_0110,
                                 _ => unreachable!()
                             };
                             if is_label {
@@ -306,11 +428,15 @@ impl Assembler {
         }
 
         //self.lines.push(line);
+        let opcode = line.instruction.clone() as u8;
+        if line.instruction != I::Dat && line.instruction != I::Org && !self.isa.has_opcode(opcode) {
+            return Err(format!("{} isn't in isa {}", instr, self.isa))
+        }
 
         Ok(Some(line))
     }
 
-    #[allow(unused_variables)]
+    #[allow(clippy::single_match)]
     fn expand_macro(&mut self, line: &str, index: usize) -> Result<(), String> {
         // comments/labels already stripped out
         let mut args = line.trim().split(' ');
@@ -329,7 +455,7 @@ impl Assembler {
             for (key, val) in &replacements {
                 l = l.replace(key, val)
             }
-            match self.read_line(&l, 0) {
+            match self.read_line(&l, index) {
                 Ok(v) => {
                     match v {
                         Some(v) => self.lines.push(v), None => {}
@@ -356,6 +482,10 @@ impl Assembler {
                 };
                 continue
             }
+            self.line_map.push((self.counter, l.program_text_line));
+            if l.instruction != Instruction::Dat {
+                self.instruction_addresses.push(self.counter)
+            }
             let instr = match self.line_to_bytes(l) {
                 Ok(bytes) => {
                     bytes.into_vec()
@@ -364,11 +494,27 @@ impl Assembler {
                     return Err(format!("line {}: {}", l.program_text_line, e))
                 }
             };
+            if !instr.is_empty() {
+                match self.segments.last_mut() {
+                    Some((_, end)) if *end == self.counter => *end += instr.len(),
+                    _ => self.segments.push((self.counter, self.counter + instr.len()))
+                }
+            }
             for b in instr {
                 set_vec_value_at_index(&mut ret, b, self.counter);
                 self.counter += 1
             }
         }
+        // org can go backwards, so segments can overlap or touch
+        self.segments.sort();
+        let mut merged: Vec<(usize, usize)> = Vec::new();
+        for (start, end) in self.segments.drain(..) {
+            match merged.last_mut() {
+                Some((_, last_end)) if start <= *last_end => *last_end = (*last_end).max(end),
+                _ => merged.push((start, end))
+            }
+        }
+        self.segments = merged;
         
         Ok(ret.into_boxed_slice())
     }
@@ -439,6 +585,71 @@ impl Assembler {
         Ok(ret.into_boxed_slice())
     }
 
+    fn resolve_annotations(&self) -> Result<Vec<Annotation>, String> {
+        let mut ret = Vec::new();
+        for a in &self.annotations {
+            let kind = match a.instr.as_str() {
+                "test"|"expect_out" => {
+                    let (literal, operand, is_label, _, _, is_string, _) = parse_op(&a.op)?;
+                    let bytes = if is_string {
+                        literal.bytes().collect()
+                    }
+                    else if is_label {
+                        let mut bytes = Vec::new();
+                        for num in literal.split(',') {
+                            bytes.push(self.get_val_from_string(num)?)
+                        }
+                        bytes
+                    }
+                    else {
+                        vec![operand as u8]
+                    };
+                    if a.instr == "test" {
+                        if !is_string {
+                            return Err(format!("line {}: test name must be a string", a.line))
+                        }
+                        AnnotationKind::Test(String::from(literal))
+                    }
+                    else {
+                        AnnotationKind::ExpectOut(bytes)
+                    }
+                }
+                _ => {
+                    let resolve = |name: &str| match self.labels.get(name) {
+                        Some(v) => Some(*v as i64),
+                        None => self.constants.get(name).map(|v| *v as i64)
+                    };
+                    match Condition::parse(&a.op, &resolve) {
+                        Ok(c) => AnnotationKind::Assert(c),
+                        Err(e) => return Err(format!("line {}: {}", a.line, e))
+                    }
+                }
+            };
+            ret.push(Annotation {
+                address: a.address,
+                line: a.line,
+                kind
+            })
+        }
+
+        Ok(ret)
+    }
+
+    /// an address or a label, for `entry` and `stack`
+    fn resolve_address(&self, directive: &Option<(String, usize)>) -> Result<Option<u16>, String> {
+        let (op, line) = match directive {
+            Some(d) => d,
+            None => return Ok(None)
+        };
+        match parse_int_literal::<u16>(op) {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => match self.labels.get(op) {
+                Some(a) => Ok(Some(*a as u16)),
+                None => Err(format!("line {}: unrecognised label {}", line, op))
+            }
+        }
+    }
+
     fn get_val_from_string(&self, s: &str) -> Result<u8, String> { // returns u8 in lb and false if it's u8
         let s = s.trim();
         match parse_int_literal::<u8>(s) {
@@ -453,6 +664,7 @@ impl Assembler {
     }
 }
 
+#[allow(clippy::type_complexity)]
 fn parse_op(op: &str) -> Result<(&str, u16, bool, bool, bool, bool, bool), String> {
     let mut literal = op;
     let is_literal = literal.starts_with('#');
@@ -535,7 +747,7 @@ enum Instruction {
 
     // stack
     Psa, Ppa,
-    Sst, Gst, // DEPRECATED
+    Gst, Sst,
     Ssp, Gsp,
 
     // misc
@@ -546,6 +758,12 @@ enum Instruction {
 
     Gbf,
 
+    // interrupts
+    Sei, Cli, Rti,
+
+    // arithmetic, continued
+    Cmp,
+
     // wide ops
     LdaAddr = 0b1000_0000,
     StaAddr, JmpAddr, JsrAddr, JezAddr, JgtAddr,
@@ -556,6 +774,16 @@ enum Instruction {
     LdaIndOffset = 0b1001_1000,
     StaIndOffset, JmpIndOffset, JsrIndOffset, JezIndOffset, JgtIndOffset, 
 
+    // branches on flags
+    JnzAddr = 0b1010_0000,
+    JcsAddr, JccAddr, JmiAddr, JplAddr, JvsAddr, JvcAddr,
+    JnzAddrOffset = 0b1010_1000,
+    JcsAddrOffset, JccAddrOffset, JmiAddrOffset, JplAddrOffset, JvsAddrOffset, JvcAddrOffset,
+    JnzInd = 0b1011_0000,
+    JcsInd, JccInd, JmiInd, JplInd, JvsInd, JvcInd,
+    JnzIndOffset = 0b1011_1000,
+    JcsIndOffset, JccIndOffset, JmiIndOffset, JplIndOffset, JvsIndOffset, JvcIndOffset,
+
     // assembler directives
     Org, Dat
 }
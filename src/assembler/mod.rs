@@ -1,20 +1,42 @@
 use std::fs::{read_to_string, write};
 
-use crate::utils::Options;
+use crate::executable::Executable;
+use crate::isa::Isa;
+use crate::utils::{Command, Options};
 
+#[allow(clippy::module_inception)]
 mod assembler;
 
-pub fn assemble(po: &Options) -> Result<Box<[u8]>, String> {
+pub use assembler::{assemble_with_debug, assemble_with_isa, closest_label, DebugInfo, Annotation, AnnotationKind};
+
+/// writes an executable with `--exe`, otherwise a raw image, with a header if `--isa` was given.
+/// the assembly returned is the raw image without a header
+pub fn assemble(po: &Options) -> Result<(Box<[u8]>, DebugInfo), String> {
     let program = match read_to_string(&po.path) {
         Ok(s) => s,
         Err(_) => return Err(format!("unable to read file {}", po.path))
     };
-    let assembly = match assembler::assemble(&program) {
-        Ok(a) => a,
-        Err(e) => return Err(e)
+    let isa = Isa::from_options(po)?;
+    let (assembly, debug) = assembler::assemble_with_isa(&program, isa.unwrap_or_default())?;
+    for (label, addr) in &debug.labels {
+        println!("label {}, pointing to {} at {}", label, assembly[*addr], addr)
+    }
+    let out = if po.executable {
+        Executable::from_assembly(&assembly, &debug, isa.unwrap_or_default(), !po.strip).to_bytes()
+    }
+    else {
+        if matches!(po.command, Command::Assemble) && (debug.entry.is_some() || debug.stack_pointer.is_some()) {
+            println!("note: a raw image always starts at 0 with sp at 0. use --exe to keep entry and stack")
+        }
+        let mut out = match isa {
+            Some(isa) => isa.header(),
+            None => Vec::new()
+        };
+        out.extend_from_slice(&assembly);
+        out
     };
-    match write(&po.out_path, &assembly) {
-        Ok(_) => Ok(assembly),
+    match write(&po.out_path, &out) {
+        Ok(_) => Ok((assembly, debug)),
         Err(_) => Err(format!("unable to write file {}", po.out_path))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,561 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write, stderr};
+
+use crate::processor::Fault;
+use crate::utils::{parse_int_literal, Options};
+
+/// a peripheral mapped into the address space. `lda` and `sta` on its range go to the device instead of ram.
+/// offsets are relative to the start of the mapping
+pub trait Device {
+    fn read(&mut self, offset: u16) -> u8;
+    fn write(&mut self, offset: u16, val: u8);
+    /// called after every instruction with the number of cycles it took
+    fn tick(&mut self, _cycles: u64) {}
+    /// whether the device is holding the interrupt line up. it should stay up until the program acknowledges it
+    fn irq(&self) -> bool {
+        false
+    }
+    /// the device's internal state, for snapshots. devices with nothing worth keeping can save nothing
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+    /// puts back state from `save_state`
+    fn load_state(&mut self, _state: &[u8]) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// a mapped device's state in a snapshot. the mapping has to match when it's loaded
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceState {
+    pub start: u16,
+    pub end: u16,
+    pub name: String,
+    pub state: Vec<u8>
+}
+
+struct Mapping {
+    start: u16,
+    end: u16, // inclusive
+    name: String,
+    device: Option<Box<dyn Device>> // none for holes in the address space
+}
+
+/// routes memory accesses to devices. anything not mapped is plain ram, which stays in `Processor::memory`
+pub struct Bus {
+    mappings: Vec<Mapping>,
+    pages: [bool; 256] // pages with a device somewhere in them, so ram accesses skip the search
+}
+impl Default for Bus {
+    fn default() -> Bus {
+        Bus {
+            mappings: Vec::new(),
+            pages: [false; 256]
+        }
+    }
+}
+
+impl Bus {
+    /// the memory map given with `-m`, if there was one, plus the timer if `--timer` was given
+    pub fn from_options(po: &Options) -> Result<Bus, String> {
+        let mut bus = if po.memory_map.is_empty() {
+            Bus::default()
+        }
+        else {
+            match std::fs::read_to_string(&po.memory_map) {
+                Ok(s) => Bus::from_config(&s)?,
+                Err(_) => return Err(format!("unable to read memory map {}", po.memory_map))
+            }
+        };
+        if po.timer {
+            bus.map(TIMER_BASE, TIMER_BASE + 4, "timer", Box::new(Timer::default()))?
+        }
+        Ok(bus)
+    }
+
+    /// parses a memory map. each line is `START END DEVICE [ARGS...]`, where START and END are inclusive
+    /// addresses, and `;` starts a comment
+    pub fn from_config(config: &str) -> Result<Bus, String> {
+        let mut bus = Bus::default();
+        for (i, l) in config.lines().enumerate() {
+            let l = l.split(';').next().unwrap().trim();
+            if l.is_empty() {
+                continue
+            }
+            let parts = l.split_whitespace().collect::<Vec<&str>>();
+            if parts.len() < 3 {
+                return Err(format!("memory map line {}: expected START END DEVICE", i + 1))
+            }
+            let start = parse_int_literal::<u16>(parts[0]).map_err(|e| format!("memory map line {}: {}", i + 1, e))?;
+            let end = parse_int_literal::<u16>(parts[1]).map_err(|e| format!("memory map line {}: {}", i + 1, e))?;
+            if parts[2] == "unmapped" {
+                bus.unmap(start, end).map_err(|e| format!("memory map line {}: {}", i + 1, e))?;
+                continue
+            }
+            let device = create_device(parts[2], &parts[3..]).map_err(|e| format!("memory map line {}: {}", i + 1, e))?;
+            bus.map(start, end, parts[2], device).map_err(|e| format!("memory map line {}: {}", i + 1, e))?;
+        }
+        Ok(bus)
+    }
+
+    pub fn map(&mut self, start: u16, end: u16, name: &str, device: Box<dyn Device>) -> Result<(), String> {
+        self.add(start, end, name, Some(device))
+    }
+    /// makes a range fault on access, instead of being ram
+    pub fn unmap(&mut self, start: u16, end: u16) -> Result<(), String> {
+        self.add(start, end, "unmapped", None)
+    }
+    fn add(&mut self, start: u16, end: u16, name: &str, device: Option<Box<dyn Device>>) -> Result<(), String> {
+        if end < start {
+            return Err(format!("mapping for {} ends before it starts", name))
+        }
+        for m in &self.mappings {
+            if start <= m.end && m.start <= end {
+                return Err(format!("{} at 0x{:0>4x}-0x{:0>4x} overlaps {}", name, start, end, m.name))
+            }
+        }
+        for page in (start >> 8)..=(end >> 8) {
+            self.pages[page as usize] = true
+        }
+        self.mappings.push(Mapping { start, end, name: String::from(name), device });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    fn find(&mut self, addr: u16) -> Option<&mut Mapping> {
+        if !self.pages[(addr >> 8) as usize] {
+            return None
+        }
+        self.mappings.iter_mut().find(|m| m.start <= addr && addr <= m.end)
+    }
+
+    /// `None` if the address is ram
+    pub fn read(&mut self, addr: u16) -> Result<Option<u8>, Fault> {
+        match self.find(addr) {
+            Some(Mapping { start, device: Some(d), .. }) => Ok(Some(d.read(addr - *start))),
+            Some(_) => Err(Fault::Unmapped(addr)),
+            None => Ok(None)
+        }
+    }
+    /// returns false if the address is ram
+    pub fn write(&mut self, addr: u16, val: u8) -> Result<bool, Fault> {
+        match self.find(addr) {
+            Some(Mapping { start, device: Some(d), .. }) => {
+                d.write(addr - *start, val);
+                Ok(true)
+            }
+            Some(_) => Err(Fault::Unmapped(addr)),
+            None => Ok(false)
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.mappings.iter().any(|m| m.device.as_ref().is_some_and(|d| d.irq()))
+    }
+
+    pub fn save_state(&self) -> Vec<DeviceState> {
+        self.mappings.iter().filter_map(|m| m.device.as_ref().map(|d| DeviceState {
+            start: m.start,
+            end: m.end,
+            name: m.name.clone(),
+            state: d.save_state()
+        })).collect()
+    }
+    /// every device in the snapshot needs a device of the same name mapped at the same place
+    pub fn load_state(&mut self, states: &[DeviceState]) -> Result<(), String> {
+        for s in states {
+            let m = self.mappings.iter_mut().find(|m| m.start == s.start && m.end == s.end && m.name == s.name);
+            match m.and_then(|m| m.device.as_mut()) {
+                Some(d) => d.load_state(&s.state).map_err(|e| format!("unable to load {} state: {}", s.name, e))?,
+                None => return Err(format!("the saved state has {} at 0x{:0>4x}-0x{:0>4x}, which isn't mapped", s.name, s.start, s.end))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        for m in &mut self.mappings {
+            if let Some(d) = &mut m.device {
+                d.tick(cycles)
+            }
+        }
+    }
+}
+
+/// the device registry. `args` are whatever followed the device name in the memory map
+pub fn create_device(name: &str, args: &[&str]) -> Result<Box<dyn Device>, String> {
+    Ok(match name {
+        "rng" => Box::new(Rng::new(match args.first() {
+            Some(s) => parse_int_literal::<u32>(s)?,
+            None => 0x2545_f491
+        })),
+        "serial" => Box::new(Serial::new(args.first().copied(), args.get(1).copied())?),
+        "timer" => Box::new(Timer::default()),
+        "storage" => match args.first() {
+            Some(path) => Box::new(Storage::new(path)?),
+            None => return Err(String::from("storage needs a backing file"))
+        },
+        _ => return Err(format!("unknown device {}", name))
+    })
+}
+
+/// xorshift random numbers. every read is a new byte, writing reseeds
+pub struct Rng {
+    state: u32
+}
+impl Rng {
+    pub fn new(seed: u32) -> Rng {
+        Rng { state: if seed == 0 { 1 } else { seed } }
+    }
+}
+impl Device for Rng {
+    fn read(&mut self, _offset: u16) -> u8 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as u8
+    }
+    fn write(&mut self, _offset: u16, val: u8) {
+        self.state = (self.state << 8) | val as u32;
+        if self.state == 0 {
+            self.state = 1
+        }
+    }
+    fn save_state(&self) -> Vec<u8> {
+        self.state.to_be_bytes().to_vec()
+    }
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        match <[u8; 4]>::try_from(state) {
+            Ok(b) if b != [0; 4] => {
+                self.state = u32::from_be_bytes(b);
+                Ok(())
+            }
+            _ => Err(String::from("bad rng state"))
+        }
+    }
+}
+
+/// a second serial port, backed by files.
+/// offset 0: data. writing sends a byte, reading takes the next input byte or 0 if there isn't one.
+/// offset 1: the number of input bytes left, capped at 255
+pub struct Serial {
+    output: Box<dyn Write>,
+    input: Vec<u8>
+}
+impl Serial {
+    /// output defaults to stderr. `-` also means stderr
+    pub fn new(output: Option<&str>, input: Option<&str>) -> Result<Serial, String> {
+        let output: Box<dyn Write> = match output {
+            None | Some("-") => Box::new(stderr()),
+            Some(path) => match File::create(path) {
+                Ok(f) => Box::new(f),
+                Err(_) => return Err(format!("unable to open serial output {}", path))
+            }
+        };
+        let mut buf = Vec::new();
+        if let Some(path) = input {
+            match File::open(path).and_then(|mut f| f.read_to_end(&mut buf)) {
+                Ok(_) => {}
+                Err(_) => return Err(format!("unable to read serial input {}", path))
+            }
+        }
+        buf.reverse(); // so pop takes from the front
+        Ok(Serial { output, input: buf })
+    }
+}
+impl Device for Serial {
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset {
+            0 => self.input.pop().unwrap_or(0),
+            1 => self.input.len().min(255) as u8,
+            _ => 0
+        }
+    }
+    fn write(&mut self, offset: u16, val: u8) {
+        if offset == 0 {
+            // a second port has nowhere to report failures, so they're dropped like a disconnected line
+            let _ = self.output.write_all(&[val]).and_then(|_| self.output.flush());
+        }
+    }
+    /// the input that hasn't been read yet. output has already gone
+    fn save_state(&self) -> Vec<u8> {
+        self.input.iter().rev().copied().collect()
+    }
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        self.input = state.iter().rev().copied().collect();
+        Ok(())
+    }
+}
+
+/// where `--timer` puts the timer's registers
+pub const TIMER_BASE: u16 = 0xfff0;
+
+/// counts down in emulated cycles, and sets its overflow flag every time it passes 0.
+/// offset 0 and 1: reload value, big endian. the timer overflows every `reload` cycles, and 0 means 65536.
+/// offset 2: control. bit 0 enables counting, bit 1 enables the interrupt, and bit 7 is the overflow flag.
+/// writing a 1 to bit 7 clears the flag. enabling the timer starts a new count from the reload value.
+/// offset 3 and 4: the current count, big endian. read only
+#[derive(Default)]
+pub struct Timer {
+    reload: u16,
+    count: u32,
+    enabled: bool,
+    irq_enabled: bool,
+    overflow: bool
+}
+impl Timer {
+    fn period(&self) -> u32 {
+        if self.reload == 0 { 65536 } else { self.reload as u32 }
+    }
+}
+impl Device for Timer {
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset {
+            0 => (self.reload >> 8) as u8,
+            1 => self.reload as u8,
+            2 => self.enabled as u8 | (self.irq_enabled as u8) << 1 | (self.overflow as u8) << 7,
+            3 => (self.count >> 8) as u8,
+            4 => self.count as u8,
+            _ => 0
+        }
+    }
+    fn write(&mut self, offset: u16, val: u8) {
+        match offset {
+            0 => self.reload = (self.reload & 0xff) | ((val as u16) << 8),
+            1 => self.reload = (self.reload & 0xff00) | val as u16,
+            2 => {
+                if val & 1 != 0 && !self.enabled {
+                    self.count = self.period()
+                }
+                self.enabled = val & 1 != 0;
+                self.irq_enabled = val & 0b10 != 0;
+                if val & 0b1000_0000 != 0 {
+                    self.overflow = false
+                }
+            }
+            _ => {}
+        }
+    }
+    fn tick(&mut self, cycles: u64) {
+        if !self.enabled {
+            return
+        }
+        let mut cycles = cycles;
+        while cycles >= self.count as u64 {
+            cycles -= self.count as u64;
+            self.overflow = true;
+            self.count = self.period()
+        }
+        self.count -= cycles as u32
+    }
+    fn irq(&self) -> bool {
+        self.overflow && self.irq_enabled
+    }
+    fn save_state(&self) -> Vec<u8> {
+        let mut ret = self.reload.to_be_bytes().to_vec();
+        ret.extend_from_slice(&self.count.to_be_bytes());
+        ret.push(self.enabled as u8 | (self.irq_enabled as u8) << 1 | (self.overflow as u8) << 7);
+        ret
+    }
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        if state.len() != 7 {
+            return Err(String::from("bad timer state"))
+        }
+        self.reload = u16::from_be_bytes([state[0], state[1]]);
+        self.count = u32::from_be_bytes([state[2], state[3], state[4], state[5]]);
+        self.enabled = state[6] & 1 != 0;
+        self.irq_enabled = state[6] & 0b10 != 0;
+        self.overflow = state[6] & 0b1000_0000 != 0;
+        Ok(())
+    }
+}
+
+/// block storage backed by a file, in 256 byte blocks.
+/// offset 0 and 1: block number, big endian.
+/// offset 2: position in the block.
+/// offset 3: data. reading or writing moves the position on by 1
+pub struct Storage {
+    file: File,
+    block: u16,
+    index: u8
+}
+impl Storage {
+    pub fn new(path: &str) -> Result<Storage, String> {
+        match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path) {
+            Ok(file) => Ok(Storage { file, block: 0, index: 0 }),
+            Err(_) => Err(format!("unable to open storage file {}", path))
+        }
+    }
+    fn position(&self) -> u64 {
+        self.block as u64 * 256 + self.index as u64
+    }
+}
+impl Device for Storage {
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset {
+            0 => (self.block >> 8) as u8,
+            1 => self.block as u8,
+            2 => self.index,
+            3 => {
+                let mut byte = [0];
+                let pos = self.position();
+                // past the end of the file reads as 0
+                let _ = self.file.seek(SeekFrom::Start(pos)).and_then(|_| self.file.read(&mut byte));
+                self.index = self.index.wrapping_add(1);
+                byte[0]
+            }
+            _ => 0
+        }
+    }
+    fn write(&mut self, offset: u16, val: u8) {
+        match offset {
+            0 => self.block = (self.block & 0xff) | ((val as u16) << 8),
+            1 => self.block = (self.block & 0xff00) | val as u16,
+            2 => self.index = val,
+            3 => {
+                let pos = self.position();
+                let _ = self.file.seek(SeekFrom::Start(pos)).and_then(|_| self.file.write_all(&[val]));
+                self.index = self.index.wrapping_add(1)
+            }
+            _ => {}
+        }
+    }
+    /// just the registers. the file is the storage, so it isn't copied
+    fn save_state(&self) -> Vec<u8> {
+        vec![(self.block >> 8) as u8, self.block as u8, self.index]
+    }
+    fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        if state.len() != 3 {
+            return Err(String::from("bad storage state"))
+        }
+        self.block = u16::from_be_bytes([state[0], state[1]]);
+        self.index = state[2];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read, remove_file};
+    use std::io;
+    use crate::assembler::assemble_with_debug;
+    use crate::processor::ProcessorBuilder;
+    use crate::utils::Options;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir().join(format!("avc-bus-test-{}-{}", name, std::process::id())).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn config() {
+        let mut bus = Bus::from_config("; devices\n0xff00 0xff00 rng 7\n\n0xff10 0xff11 serial - ; second port").unwrap();
+        assert!(bus.read(0xff00).unwrap().is_some());
+        assert_eq!(bus.read(0xff11), Ok(Some(0)));
+        assert_eq!(bus.read(0xff01), Ok(None));
+        assert_eq!(bus.write(0x0300, 1), Ok(false));
+        let mut bus = Bus::from_config("0x8000 0x8fff unmapped").unwrap();
+        assert_eq!(bus.write(0x8001, 1), Err(Fault::Unmapped(0x8001)));
+        assert!(Bus::from_config("0xff00 0xff10 rng\n0xff10 0xff20 rng").is_err());
+        assert!(Bus::from_config("0xff00 0xff10 toaster").is_err());
+    }
+
+    #[test]
+    fn timer() {
+        let mut t = Timer::default();
+        t.write(1, 10);
+        t.write(2, 0b11);
+        t.tick(9);
+        assert_eq!((t.read(4), t.irq()), (1, false));
+        t.tick(1);
+        assert_eq!((t.read(4), t.irq()), (10, true));
+        t.tick(25);
+        assert_eq!(t.read(4), 5);
+        t.write(2, 0b1000_0011); // acknowledge
+        assert!(!t.irq());
+        assert_eq!(t.read(2), 0b11);
+        t.write(2, 0);
+        t.tick(100);
+        assert_eq!(t.read(4), 5);
+
+        let mut copy = Timer::default();
+        copy.load_state(&t.save_state()).unwrap();
+        assert_eq!((0..5).map(|i| copy.read(i)).collect::<Vec<u8>>(), (0..5).map(|i| t.read(i)).collect::<Vec<u8>>());
+        assert!(copy.load_state(&[1]).is_err())
+    }
+
+    #[test]
+    fn storage() {
+        let path = temp_path("storage");
+        let mut s = Storage::new(&path).unwrap();
+        s.write(1, 2); // block 2
+        s.write(2, 0xfe);
+        for b in b"abc" {
+            s.write(3, *b)
+        }
+        assert_eq!((s.read(0), s.read(1), s.read(2)), (0, 2, 1)); // the position wraps within the block
+        let file = read(&path).unwrap();
+        assert_eq!((file.len(), file[512], &file[766..]), (768, b'c', &b"ab"[..]));
+        s.write(2, 0xfe);
+        assert_eq!((s.read(3), s.read(3)), (b'a', b'b'));
+        s.write(1, 7);
+        assert_eq!(s.read(3), 0); // past the end of the file
+
+        let mut copy = Storage::new(&path).unwrap();
+        s.write(1, 2);
+        s.write(2, 0xff);
+        copy.load_state(&s.save_state()).unwrap();
+        assert_eq!((copy.read(1), copy.read(2), copy.read(3)), (2, 0xff, b'b'));
+        assert!(copy.load_state(&[1, 2]).is_err());
+        remove_file(&path).unwrap()
+    }
+
+    #[test]
+    fn serial() {
+        let (out_path, in_path) = (temp_path("serial-out"), temp_path("serial-in"));
+        std::fs::write(&in_path, b"xy").unwrap();
+        let mut s = Serial::new(Some(&out_path), Some(&in_path)).unwrap();
+        assert_eq!((s.read(1), s.read(0), s.read(1)), (2, b'x', 1));
+        let mut copy = Serial::new(Some(&out_path), None).unwrap();
+        copy.load_state(&s.save_state()).unwrap();
+        assert_eq!((copy.read(0), copy.read(0), copy.read(1)), (b'y', 0, 0));
+        s.write(0, b'o');
+        s.write(0, b'k');
+        s.write(1, b'!'); // not the data register
+        assert_eq!(read(&out_path).unwrap(), b"ok");
+        remove_file(&out_path).unwrap();
+        remove_file(&in_path).unwrap()
+    }
+
+    /// the devices as a program sees them, through lda and sta
+    #[test]
+    fn from_a_program() {
+        let (storage_path, serial_path) = (temp_path("program-storage"), temp_path("program-serial"));
+        let bus = Bus::from_config(&format!("0xff00 0xff03 storage {}\n0xff10 0xff11 serial {}", storage_path, serial_path)).unwrap();
+        let (prog, _) = assemble_with_debug("
+        lda #1
+        sta 0xff01
+        lda #0x68
+        sta 0xff03
+        lda #0x69
+        sta 0xff03
+        lda #0
+        sta 0xff02
+        lda 0xff03
+        sta 0xff10
+        lda 0xff03
+        sta 0xff10
+        hlt").unwrap();
+        let mut p = ProcessorBuilder::new().memory(&prog).bus(bus).output(io::sink()).input(io::empty()).build();
+        while !p.halted {
+            p.execute(false, &Options::default());
+        }
+        assert_eq!(&read(&storage_path).unwrap()[256..], b"hi");
+        assert_eq!(read(&serial_path).unwrap(), b"hi");
+        remove_file(&storage_path).unwrap();
+        remove_file(&serial_path).unwrap()
+    }
+}
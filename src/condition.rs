@@ -0,0 +1,173 @@
+use std::fmt;
+
+use crate::processor::Processor;
+use crate::utils::parse_int_literal;
+
+/// a comparison between two pieces of machine state, like `a == 0x10` or `mem[BUF] != 0`
+#[derive(Clone)]
+pub struct Condition {
+    lhs: Value,
+    cmp: Comparison,
+    rhs: Value,
+    text: String
+}
+
+#[derive(Clone)]
+enum Value {
+    A, B, X, Status, Pc, Sp,
+    Carry, Zero, Negative, Overflow,
+    Hits,
+    Mem(u16),
+    Int(i64)
+}
+
+#[derive(Clone, Copy)]
+enum Comparison {
+    Eq, Ne, Le, Ge, Lt, Gt
+}
+
+impl Condition {
+    /// `resolve` looks up label and constant names
+    pub fn parse(s: &str, resolve: &dyn Fn(&str) -> Option<i64>) -> Result<Condition, String> {
+        let ops = [
+            ("==", Comparison::Eq), ("!=", Comparison::Ne),
+            ("<=", Comparison::Le), (">=", Comparison::Ge),
+            ("<", Comparison::Lt), (">", Comparison::Gt)
+        ];
+        for (op, cmp) in ops { // two char ops first so < doesn't eat <=
+            if let Some((lhs, rhs)) = s.split_once(op) {
+                return Ok(Condition {
+                    lhs: parse_value(lhs.trim(), resolve)?,
+                    cmp,
+                    rhs: parse_value(rhs.trim(), resolve)?,
+                    text: String::from(s.trim())
+                })
+            }
+        }
+        Err(format!("condition \"{}\" has no comparison", s))
+    }
+
+    pub fn eval(&self, p: &Processor) -> bool {
+        self.eval_with_hits(p, 0)
+    }
+    /// for breakpoints, where `hits` is how many times the breakpoint has been reached
+    pub fn eval_with_hits(&self, p: &Processor, hits: u64) -> bool {
+        let lhs = value_of(&self.lhs, p, hits);
+        let rhs = value_of(&self.rhs, p, hits);
+        match self.cmp {
+            Comparison::Eq => lhs == rhs,
+            Comparison::Ne => lhs != rhs,
+            Comparison::Le => lhs <= rhs,
+            Comparison::Ge => lhs >= rhs,
+            Comparison::Lt => lhs < rhs,
+            Comparison::Gt => lhs > rhs,
+        }
+    }
+
+    /// what the left hand side actually was, for error messages
+    pub fn explain(&self, p: &Processor) -> String {
+        let lhs = self.text.split(['=', '!', '<', '>']).next().unwrap().trim();
+        format!("{} is 0x{:x}", lhs, value_of(&self.lhs, p, 0))
+    }
+}
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn parse_value(s: &str, resolve: &dyn Fn(&str) -> Option<i64>) -> Result<Value, String> {
+    Ok(match s {
+        "a" => Value::A,
+        "b" => Value::B,
+        "x" => Value::X,
+        "s" => Value::Status,
+        "pc" => Value::Pc,
+        "sp" => Value::Sp,
+        "c" => Value::Carry,
+        "z" => Value::Zero,
+        "n" => Value::Negative,
+        "v" => Value::Overflow,
+        "hits" => Value::Hits,
+        _ => {
+            if s.starts_with("mem[") && s.ends_with(']') {
+                let addr = parse_number(&s[4..s.len() - 1], resolve)?;
+                Value::Mem(addr as u16)
+            }
+            else {
+                Value::Int(parse_number(s, resolve)?)
+            }
+        }
+    })
+}
+
+/// an integer literal, or a name with optional label arithmetic, like `BUF+1`
+pub fn parse_number(s: &str, resolve: &dyn Fn(&str) -> Option<i64>) -> Result<i64, String> {
+    let s = s.trim();
+    let s = s.strip_prefix('#').unwrap_or(s);
+    if s.is_empty() {
+        return Err(String::from("missing value in condition"))
+    }
+    if let Ok(v) = parse_int_literal::<i64>(s) {
+        return Ok(v)
+    }
+    // label arithmetic, same as the assembler
+    let (name, addition) = match s.find(['+', '-']) {
+        Some(i) => {
+            let offset = parse_int_literal::<i64>(s[i + 1..].trim())?;
+            (s[..i].trim(), if &s[i..i + 1] == "-" { -offset } else { offset })
+        }
+        None => (s, 0)
+    };
+    match resolve(name) {
+        Some(v) => Ok(v + addition),
+        None => Err(format!("unrecognised name {} in condition", name))
+    }
+}
+
+fn value_of(v: &Value, p: &Processor, hits: u64) -> i64 {
+    match v {
+        Value::A => p.a as i64,
+        Value::B => p.b as i64,
+        Value::X => p.x as i64,
+        Value::Status => p.status as i64,
+        Value::Pc => p.program_counter as i64,
+        Value::Sp => p.stack_pointer as i64,
+        Value::Carry => (p.status & 1) as i64,
+        Value::Zero => ((p.status >> 1) & 1) as i64,
+        Value::Negative => (p.status >> 7) as i64,
+        Value::Overflow => ((p.status >> 6) & 1) as i64,
+        Value::Hits => hits as i64,
+        Value::Mem(addr) => p.memory[*addr as usize] as i64,
+        Value::Int(i) => *i
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::ProcessorBuilder;
+
+    fn resolve(name: &str) -> Option<i64> {
+        match name {
+            "BUF" => Some(0x300),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn parse_and_eval() {
+        let mut p = ProcessorBuilder::new().output(std::io::sink()).input(std::io::empty()).build();
+        p.a = 0x10;
+        p.memory[0x301] = 5;
+        assert!(Condition::parse("a == 0x10", &resolve).unwrap().eval(&p));
+        assert!(Condition::parse("a <= 16", &resolve).unwrap().eval(&p));
+        assert!(!Condition::parse("a < 0x10", &resolve).unwrap().eval(&p));
+        assert!(Condition::parse("mem[BUF+1] != 0", &resolve).unwrap().eval(&p));
+        assert!(Condition::parse("mem[BUF] == #0", &resolve).unwrap().eval(&p));
+        assert!(Condition::parse("c == 0", &resolve).unwrap().eval(&p));
+        assert!(Condition::parse("hits >= 3", &resolve).unwrap().eval_with_hits(&p, 3));
+        assert!(Condition::parse("mem[NOPE] == 0", &resolve).is_err());
+        assert!(Condition::parse("a = 1", &resolve).is_err());
+    }
+}
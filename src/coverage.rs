@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use std::fs::{read_to_string, write};
+
+use crate::assembler::{assemble_with_isa, DebugInfo};
+use crate::isa::Isa;
+use crate::processor::is_conditional_branch;
+use crate::utils::Options;
+
+/// which instructions have run, and which way each conditional branch went
+pub struct Coverage {
+    counts: Vec<u64>, // executions of the instruction at each address
+    branches: BTreeMap<u16, [u64; 2]> // times each branch was taken and not taken
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage { counts: vec![0; 65536], branches: BTreeMap::new() }
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    pub(crate) fn executed(&mut self, pc: u16) {
+        self.counts[pc as usize] += 1
+    }
+    pub(crate) fn branch(&mut self, pc: u16, taken: bool) {
+        self.branches.entry(pc).or_default()[if taken { 0 } else { 1 }] += 1
+    }
+
+    /// how many times the instruction at `addr` ran
+    pub fn count(&self, addr: u16) -> u64 {
+        self.counts[addr as usize]
+    }
+    /// how many times the branch at `addr` was taken and not taken, if it ever ran
+    pub fn branch_counts(&self, addr: u16) -> Option<[u64; 2]> {
+        self.branches.get(&addr).copied()
+    }
+
+    /// adds another run's coverage to this one
+    pub fn merge(&mut self, other: &Coverage) {
+        for (c, o) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *c += o
+        }
+        for (addr, [taken, not_taken]) in &other.branches {
+            let b = self.branches.entry(*addr).or_default();
+            b[0] += taken;
+            b[1] += not_taken
+        }
+    }
+
+    /// an lcov tracefile for one source file. a line's count is the most any of its instructions ran, since a
+    /// macro puts several on one line. every conditional branch is a block with a taken and a not taken branch
+    pub fn lcov(&self, debug: &DebugInfo, program: &[u8], source_path: &str) -> String {
+        let mut lines: BTreeMap<usize, u64> = BTreeMap::new();
+        let mut branches: BTreeMap<usize, Vec<Option<[u64; 2]>>> = BTreeMap::new(); // by line, None if never run
+        for addr in &debug.instructions {
+            let line = match debug.line_at(*addr) {
+                Some(l) => l,
+                None => continue
+            };
+            let count = self.counts[*addr];
+            let c = lines.entry(line).or_default();
+            *c = (*c).max(count);
+
+            let op = program.get(*addr).copied().unwrap_or(0);
+            if is_conditional_branch(op) {
+                let taken = if count == 0 { None } else { Some(self.branches.get(&(*addr as u16)).copied().unwrap_or_default()) };
+                branches.entry(line).or_default().push(taken)
+            }
+        }
+
+        let mut ret = format!("TN:\nSF:{}\n", source_path);
+        let (mut found, mut hit) = (0, 0);
+        for (line, b) in &branches {
+            for (block, counts) in b.iter().enumerate() {
+                for branch in 0..2 {
+                    found += 1;
+                    let taken = match counts {
+                        Some(c) => {
+                            if c[branch] != 0 {
+                                hit += 1
+                            }
+                            c[branch].to_string()
+                        }
+                        None => String::from("-") // the branch never ran
+                    };
+                    ret.push_str(&format!("BRDA:{},{},{},{}\n", line, block, branch, taken))
+                }
+            }
+        }
+        ret.push_str(&format!("BRF:{}\nBRH:{}\n", found, hit));
+        for (line, count) in &lines {
+            ret.push_str(&format!("DA:{},{}\n", line, count))
+        }
+        ret.push_str(&format!("LF:{}\nLH:{}\n", lines.len(), lines.values().filter(|c| **c != 0).count()));
+        ret.push_str("end_of_record\n");
+        ret
+    }
+}
+
+/// for `--coverage`, once the program has run. `-R` only keeps the binary, so the source is assembled again
+/// for its debug info
+pub fn write_lcov(coverage: &Coverage, po: &Options) -> Result<(), String> {
+    let program = match read_to_string(&po.path) {
+        Ok(s) => s,
+        Err(_) => return Err(format!("unable to read file {}", po.path))
+    };
+    let (prog, debug) = assemble_with_isa(&program, Isa::from_options(po)?.unwrap_or_default())?;
+    match write(&po.coverage, coverage.lcov(&debug, &prog, &po.path)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("unable to write coverage to {}: {}", po.coverage, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::assembler::assemble_with_debug;
+    use crate::processor::ProcessorBuilder;
+
+    #[test]
+    fn lcov() {
+        let src = "
+        lda #2
+        tab
+LOOP:   inc
+        txa
+        jgt DONE
+        jmp LOOP
+DONE:   lda #0
+        jez END
+        hlt
+        lda #1
+END:    hlt
+        dat 5";
+        let (prog, debug) = assemble_with_debug(src).unwrap();
+        let mut p = ProcessorBuilder::new().memory(&prog).output(io::sink()).input(io::empty()).build();
+        p.coverage = Some(Coverage::new());
+        while !p.halted {
+            p.step().unwrap();
+        }
+        let c = p.coverage.take().unwrap();
+        let jgt = debug.labels["LOOP"] as u16 + 2;
+        assert_eq!(c.branch_counts(jgt), Some([1, 2]));
+        assert_eq!(c.count(debug.labels["LOOP"] as u16), 3);
+
+        let mut merged = Coverage::new();
+        merged.merge(&c);
+        merged.merge(&c);
+        assert_eq!(merged.branch_counts(jgt), Some([2, 4]));
+
+        let lcov = c.lcov(&debug, &p.memory, "loop.avc");
+        assert_eq!(lcov, "TN:\nSF:loop.avc\n\
+            BRDA:6,0,0,1\nBRDA:6,0,1,2\nBRDA:9,0,0,1\nBRDA:9,0,1,0\nBRF:4\nBRH:3\n\
+            DA:2,1\nDA:3,1\nDA:4,3\nDA:5,3\nDA:6,3\nDA:7,2\nDA:8,1\nDA:9,1\nDA:10,0\nDA:11,0\nDA:12,1\n\
+            LF:11\nLH:9\nend_of_record\n");
+    }
+}
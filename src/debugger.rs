@@ -0,0 +1,650 @@
+use std::fs::read_to_string;
+use std::io::{self, BufRead, Write};
+
+use crate::assembler::{assemble_with_isa, closest_label, DebugInfo};
+use crate::bus::Bus;
+use crate::condition::{parse_number, Condition};
+use crate::disassembler::disassemble;
+use crate::hexdump::{self, hexdump};
+use crate::isa::Isa;
+use crate::processor::{Breakpoint, Processor, ProcessorBuilder, StepOutcome, Watchpoint};
+use crate::utils::Options;
+
+const HELP: &str = "\
+s, step [N]        execute N instructions (default 1)
+n, next            step, but run a jsr until it returns
+fin, finish        run until the current routine returns
+c, continue        run until a breakpoint, brk or hlt
+b, break WHERE [if COND]
+                   set a breakpoint at an address or label, optionally only stopping
+                   when COND holds. COND can use hits, the times it's been reached
+watch ADDR [END]   stop after memory from ADDR to END is written
+rwatch ADDR [END]  stop after it's read
+awatch ADDR [END]  stop after it's read or written
+d, delete N        delete breakpoint N
+unwatch N          delete watchpoint N
+i, info            list breakpoints and watchpoints
+x ADDR [N], x/N ADDR
+                   examine N bytes of memory (default 16) as hex and ascii
+dump ADDR END FILE write the bytes from ADDR to END to FILE
+set REG VAL        set a, b, x, s, pc or sp
+set ADDR VAL       set a byte of memory
+r, regs            show the registers
+dis [N]            disassemble N instructions from the pc (default 8), after the few before it
+l, list            show the source around the pc
+in TEXT            queue TEXT, and a newline, as program input
+rs, reverse-step [N]
+                   undo N instructions (default 1)
+rc, reverse-continue
+                   run backwards to a breakpoint or a write to a watched address
+who ADDR           show which instruction last wrote to an address
+record N           keep the last N instructions for running backwards, 0 to stop
+q, quit            exit";
+
+/// how many instructions the debugger can undo, unless it's told otherwise with `record`
+const DEFAULT_HISTORY: usize = 100_000;
+
+/// how many instructions `dis` shows before the pc
+const DIS_BEFORE: usize = 3;
+
+/// an interactive debugger for a program assembled from source, so it can talk in labels and lines
+pub struct Debugger {
+    pub processor: Processor,
+    debug: DebugInfo,
+    source: Vec<String>
+}
+
+/// what a command asked the repl to do next
+pub enum Action {
+    Continue,
+    Quit
+}
+
+impl Debugger {
+    pub fn new(program: &str, processor: ProcessorBuilder) -> Result<Debugger, String> {
+        let (prog, debug) = assemble_with_isa(program, processor.isa)?;
+        let mut processor = processor.memory(&prog).build();
+        processor.start_at_entry(&debug);
+        processor.record_history(DEFAULT_HISTORY);
+        Ok(Debugger {
+            processor,
+            debug,
+            source: program.lines().map(String::from).collect()
+        })
+    }
+
+    /// runs one command, writing what it has to say to `out`
+    pub fn command(&mut self, line: &str, out: &mut dyn Write) -> io::Result<Action> {
+        let mut args = line.split_whitespace();
+        let cmd = match args.next() {
+            Some(c) => c,
+            None => return Ok(Action::Continue)
+        };
+        let args = args.collect::<Vec<&str>>();
+        let result = match cmd {
+            "s"|"step" => {
+                let n = match args.first() {
+                    Some(n) => self.number(n).map(|n| n as usize),
+                    None => Ok(1)
+                };
+                match n {
+                    Ok(n) => {
+                        for _ in 0..n {
+                            if !self.step(out)? {
+                                break
+                            }
+                        }
+                        self.location()
+                    }
+                    Err(e) => Err(e)
+                }
+            }
+            "n"|"next" => {
+                let pc = self.processor.program_counter;
+                if self.processor.memory[pc as usize] & 0b1110_0111 == 0b1000_0011 { // any jsr
+                    // routines can return somewhere other than just after the jsr, so wait for the stack to
+                    // come back down instead of watching for the return address
+                    let sp = self.processor.stack_pointer;
+                    self.run_until(out, |p, op| op == 24 && p.stack_pointer == sp)?
+                }
+                else {
+                    self.step(out)?;
+                    self.location()
+                }
+            }
+            // the return address is just under the stack pointer when the routine starts,
+            // so the rts that returns from it leaves the stack pointer below that
+            "fin"|"finish" => match self.processor.stack_pointer.checked_sub(2) {
+                Some(frame) => self.run_until(out, |p, op| op == 24 && p.stack_pointer <= frame)?,
+                None => Err(String::from("no frame to finish, the stack has no return address on it"))
+            },
+            "c"|"continue" => self.run_until(out, |_, _| false)?,
+            "b"|"break" => match args.first() {
+                Some(a) => self.add_breakpoint(a, &args[1..]),
+                None => Err(String::from("break needs an address or label"))
+            },
+            "watch"|"rwatch"|"awatch" => match args.first() {
+                Some(a) => self.add_watchpoint(cmd, a, args.get(1).copied()),
+                None => Err(format!("{} needs an address", cmd))
+            },
+            "d"|"delete" => match args.first().map(|n| self.number(n)) {
+                Some(Ok(n)) if (n as usize) < self.processor.breakpoints.len() => {
+                    self.processor.breakpoints.remove(n as usize);
+                    Ok(format!("deleted breakpoint {}", n))
+                }
+                _ => Err(String::from("no such breakpoint"))
+            },
+            "unwatch" => match args.first().map(|n| self.number(n)) {
+                Some(Ok(n)) if (n as usize) < self.processor.watchpoints.len() => {
+                    self.processor.watchpoints.remove(n as usize);
+                    Ok(format!("deleted watchpoint {}", n))
+                }
+                _ => Err(String::from("no such watchpoint"))
+            },
+            "i"|"info" => Ok(self.info()),
+            "x" => match args.first() {
+                Some(a) => {
+                    let count = match args.get(1) {
+                        Some(n) => self.number(n),
+                        None => Ok(16)
+                    };
+                    match (self.address(a), count) {
+                        (Ok(addr), Ok(count)) => self.examine(addr, count),
+                        (Err(e), _)|(_, Err(e)) => Err(e)
+                    }
+                }
+                None => Err(String::from("x needs an address"))
+            },
+            c if c.starts_with("x/") => match (self.number(&c[2..]), args.first()) {
+                (Ok(count), Some(a)) => self.address(a).and_then(|addr| self.examine(addr, count)),
+                (Err(e), _) => Err(e),
+                (_, None) => Err(String::from("usage: x/N ADDR"))
+            },
+            "dump" => {
+                if args.len() != 3 {
+                    Err(String::from("usage: dump ADDR END FILE"))
+                }
+                else {
+                    match (self.address(args[0]), self.address(args[1])) {
+                        (Ok(start), Ok(end)) if end >= start => hexdump::write_regions(&self.processor.memory, &[(start, end)], args[2])
+                            .map(|_| format!("wrote {} bytes to {}", end as usize - start as usize + 1, args[2])),
+                        (Ok(_), Ok(_)) => Err(String::from("the range ends before it starts")),
+                        (Err(e), _)|(_, Err(e)) => Err(e)
+                    }
+                }
+            }
+            "set" => {
+                if args.len() != 2 {
+                    Err(String::from("usage: set REG|ADDR VAL"))
+                }
+                else {
+                    self.set(args[0], args[1])
+                }
+            }
+            "r"|"regs" => Ok(String::from(self.processor.readout().trim_end())),
+            "dis" => {
+                let n = match args.first() {
+                    Some(n) => self.number(n),
+                    None => Ok(8)
+                };
+                n.map(|n| self.disassembly(n as usize))
+            }
+            "l"|"list" => Ok(self.listing()),
+            "in" => {
+                let text = line.trim_start()[2..].trim_start();
+                self.processor.get_buffer.extend_from_slice(text.as_bytes());
+                self.processor.get_buffer.push(b'\n');
+                Ok(format!("{} bytes queued", text.len() + 1))
+            }
+            "rs"|"reverse-step" => {
+                let n = match args.first() {
+                    Some(n) => self.number(n).map(|n| n as usize),
+                    None => Ok(1)
+                };
+                match n {
+                    Ok(n) => {
+                        for _ in 0..n {
+                            if self.processor.step_back().is_none() {
+                                writeln!(out, "reached the start of the recorded history")?;
+                                break
+                            }
+                        }
+                        self.location()
+                    }
+                    Err(e) => Err(e)
+                }
+            }
+            "rc"|"reverse-continue" => self.reverse_continue(out)?,
+            "who" => match args.first() {
+                Some(a) => self.address(a).map(|addr| self.who_wrote(addr)),
+                None => Err(String::from("who needs an address"))
+            },
+            "record" => match args.first().map(|n| self.number(n)) {
+                Some(Ok(n)) if n >= 0 => {
+                    self.processor.record_history(n as usize);
+                    Ok(String::new())
+                }
+                _ => Err(String::from("usage: record N"))
+            },
+            "h"|"help" => Ok(String::from(HELP)),
+            "q"|"quit" => return Ok(Action::Quit),
+            _ => Err(format!("unknown command {}, try help", cmd))
+        };
+        match result {
+            Ok(s) if s.is_empty() => {}
+            Ok(s) => writeln!(out, "{}", s)?,
+            Err(e) => writeln!(out, "error: {}", e)?
+        }
+        Ok(Action::Continue)
+    }
+
+    /// returns false if the processor can't go any further
+    fn step(&mut self, out: &mut dyn Write) -> io::Result<bool> {
+        if self.processor.halted {
+            writeln!(out, "the program has halted")?;
+            return Ok(false)
+        }
+        match self.processor.step() {
+            Ok(StepOutcome::Halted) => {
+                writeln!(out, "\nhalted")?;
+                Ok(false)
+            }
+            Ok(StepOutcome::Trapped(f)) => {
+                writeln!(out, "\ntrapped {}", f)?;
+                Ok(true)
+            }
+            Ok(StepOutcome::Breakpoint(i)) => {
+                writeln!(out, "breakpoint {} at {}", i, self.describe(self.processor.program_counter))?;
+                Ok(false)
+            }
+            Ok(StepOutcome::Watchpoint(hit)) => {
+                let what = if hit.write { "wrote" } else { "read" };
+                writeln!(out, "watchpoint {}: {} 0x{:0>2x} at {} from {}",
+                    hit.index, what, hit.value, self.describe_data(hit.address), self.describe(hit.pc))?;
+                Ok(false)
+            }
+            Ok(_) => Ok(true),
+            Err(f) => {
+                writeln!(out, "\nfault at {}: {}", self.describe(self.processor.fault_pc), f)?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// `stop` is given the processor and the opcode that was just executed
+    fn run_until(&mut self, out: &mut dyn Write, stop: impl Fn(&Processor, u8) -> bool) -> io::Result<Result<String, String>> {
+        loop {
+            if !self.step(out)? {
+                break
+            }
+            // not necessarily what the pc was on, if the step took an interrupt
+            let (pc, op) = match self.processor.last_instr {
+                Some(i) => i,
+                None => break
+            };
+            if op == 23 {
+                writeln!(out, "brk at {}", self.describe(pc))?;
+                break
+            }
+            if stop(&self.processor, op) {
+                break
+            }
+        }
+        Ok(self.location())
+    }
+
+    /// stops on breakpoints whose conditions hold now, and on undoing a write a write watchpoint covers
+    fn reverse_continue(&mut self, out: &mut dyn Write) -> io::Result<Result<String, String>> {
+        loop {
+            let writes = match self.processor.step_back() {
+                Some(w) => w,
+                None => {
+                    writeln!(out, "reached the start of the recorded history")?;
+                    break
+                }
+            };
+            let p = &self.processor;
+            let watched = p.watchpoints.iter().position(|w| w.write && writes.iter().any(|m| m.address >= w.start && m.address <= w.end));
+            if let Some(i) = watched {
+                writeln!(out, "watchpoint {}", i)?;
+                break
+            }
+            let pc = p.program_counter;
+            let hit = p.breakpoints.iter().position(|b| b.address == pc && match &b.condition {
+                Some(c) => c.eval_with_hits(p, b.hits),
+                None => true
+            });
+            if let Some(i) = hit {
+                writeln!(out, "breakpoint {} at {}", i, self.describe(pc))?;
+                break
+            }
+        }
+        Ok(self.location())
+    }
+
+    fn who_wrote(&self, addr: u16) -> String {
+        let found = match &self.processor.history {
+            Some(h) => h.last_write(addr),
+            None => return String::from("history isn't being recorded")
+        };
+        match found {
+            Some(w) => format!("{} was last written by {}, 0x{:0>2x} -> 0x{:0>2x}, {} instructions ago",
+                self.describe_data(addr), self.describe(w.pc), w.write.old, w.write.new, w.steps_ago),
+            None => format!("{} hasn't been written in the recorded history", self.describe_data(addr))
+        }
+    }
+
+    fn resolve(&self, name: &str) -> Option<i64> {
+        self.debug.labels.get(name).map(|a| *a as i64)
+    }
+    fn number(&self, s: &str) -> Result<i64, String> {
+        parse_number(s, &|n| self.resolve(n))
+    }
+    fn address(&self, s: &str) -> Result<u16, String> {
+        match self.number(s)? {
+            a @ 0..=0xffff => Ok(a as u16),
+            a => Err(format!("address {} out of range", a))
+        }
+    }
+
+    fn add_breakpoint(&mut self, at: &str, rest: &[&str]) -> Result<String, String> {
+        let addr = self.address(at)?;
+        let condition = match rest.split_first() {
+            Some((&"if", cond)) => Some(Condition::parse(&cond.join(" "), &|n| self.resolve(n))?),
+            Some(_) => return Err(String::from("usage: break WHERE [if COND]")),
+            None => None
+        };
+        self.processor.breakpoints.push(Breakpoint::new(addr, condition));
+        Ok(format!("breakpoint {} at {}", self.processor.breakpoints.len() - 1, self.describe(addr)))
+    }
+    fn add_watchpoint(&mut self, kind: &str, start: &str, end: Option<&str>) -> Result<String, String> {
+        let start = self.address(start)?;
+        let end = match end {
+            Some(e) => self.address(e)?,
+            None => start
+        };
+        if end < start {
+            return Err(String::from("watchpoint ends before it starts"))
+        }
+        let (read, write) = match kind {
+            "watch" => (false, true),
+            "rwatch" => (true, false),
+            _ => (true, true)
+        };
+        self.processor.watchpoints.push(Watchpoint::new(start, end, read, write));
+        Ok(format!("watchpoint {} on {}", self.processor.watchpoints.len() - 1, self.describe_range(start, end)))
+    }
+
+    fn info(&self) -> String {
+        let mut ret = String::new();
+        for (i, b) in self.processor.breakpoints.iter().enumerate() {
+            ret.push_str(&format!("breakpoint {}: {}", i, self.describe(b.address)));
+            if let Some(c) = &b.condition {
+                ret.push_str(&format!(" if {}", c))
+            }
+            ret.push_str(&format!(", hit {} times\n", b.hits))
+        }
+        for (i, w) in self.processor.watchpoints.iter().enumerate() {
+            let kind = match (w.read, w.write) {
+                (true, true) => "access",
+                (true, false) => "read",
+                _ => "write"
+            };
+            ret.push_str(&format!("watchpoint {}: {} {}, hit {} times\n", i, kind, self.describe_range(w.start, w.end), w.hits))
+        }
+        String::from(ret.trim_end())
+    }
+
+    fn set(&mut self, target: &str, val: &str) -> Result<String, String> {
+        let v = self.number(val)?;
+        let p = &mut self.processor;
+        match target {
+            "a" => p.a = v as u8,
+            "b" => p.b = v as u8,
+            "x" => p.x = v as u8,
+            "s" => p.status = v as u8,
+            "pc" => p.program_counter = v as u16,
+            "sp" => p.stack_pointer = v as u16,
+            _ => {
+                let addr = self.address(target)?;
+                self.processor.memory[addr as usize] = v as u8
+            }
+        }
+        Ok(String::new())
+    }
+
+    /// an address with its label and source line, if it has them
+    fn describe(&self, addr: u16) -> String {
+        let mut ret = format!("0x{:0>4x}", addr);
+        if let Some(s) = self.debug.symbolize(addr as usize) {
+            ret.push_str(&format!(" <{}>", s))
+        }
+        if let Some(l) = self.debug.line_at(addr as usize) {
+            ret.push_str(&format!(" (line {})", l))
+        }
+        ret
+    }
+
+    /// data addresses just get their label, since they don't have useful lines
+    fn describe_data(&self, addr: u16) -> String {
+        match self.debug.symbolize(addr as usize) {
+            Some(s) => format!("0x{:0>4x} <{}>", addr, s),
+            None => format!("0x{:0>4x}", addr)
+        }
+    }
+    fn describe_range(&self, start: u16, end: u16) -> String {
+        if start == end {
+            self.describe_data(start)
+        }
+        else {
+            format!("{} to {}", self.describe_data(start), self.describe_data(end))
+        }
+    }
+
+    fn location(&self) -> Result<String, String> {
+        let pc = self.processor.program_counter;
+        let (instr, _) = disassemble(&self.processor.memory, pc as usize, self.processor.isa, Some(&self.debug.labels));
+        Ok(format!("{}: {}", self.describe(pc), instr))
+    }
+
+    fn examine(&self, addr: u16, count: i64) -> Result<String, String> {
+        if count < 1 || addr as i64 + count > 0x10000 {
+            return Err(format!("can't examine {} bytes from 0x{:0>4x}", count, addr))
+        }
+        let end = (addr as i64 + count - 1) as u16;
+        Ok(String::from(hexdump(&self.processor.memory, addr, end, Some(&self.debug.labels)).trim_end()))
+    }
+
+    fn disassembly(&self, n: usize) -> String {
+        let pc = self.processor.program_counter as usize;
+        // going backwards from the pc can't be done by decoding, so lead in with the assembler's instructions before it
+        let mut before: Vec<usize> = self.debug.instructions.iter().copied().filter(|a| *a < pc).collect();
+        before.sort_unstable();
+        let mut addrs: Vec<usize> = before.into_iter().rev().take(DIS_BEFORE).collect();
+        addrs.reverse();
+        let mut addr = pc;
+        for _ in 0..n {
+            addrs.push(addr);
+            addr = (addr + disassemble(&self.processor.memory, addr, self.processor.isa, None).1) & 0xffff
+        }
+        let mut ret = String::new();
+        for addr in addrs {
+            if let Some((label, _)) = closest_label(&self.debug.labels, addr).filter(|(_, at)| *at == addr) {
+                ret.push_str(&format!("{}:\n", label))
+            }
+            let (text, _) = disassemble(&self.processor.memory, addr, self.processor.isa, Some(&self.debug.labels));
+            let marker = if addr == pc { "=>" } else { "  " };
+            ret.push_str(&format!("{} 0x{:0>4x}  {}\n", marker, addr, text))
+        }
+        String::from(ret.trim_end())
+    }
+
+    fn listing(&self) -> String {
+        let line = match self.debug.line_at(self.processor.program_counter as usize) {
+            Some(l) => l,
+            None => return String::from("no source for this address")
+        };
+        let mut ret = String::new();
+        for l in line.saturating_sub(5).max(1)..=(line + 5).min(self.source.len()) {
+            let marker = if l == line { "=>" } else { "  " };
+            ret.push_str(&format!("{} {:>4} {}\n", marker, l, self.source[l - 1]))
+        }
+        String::from(ret.trim_end())
+    }
+}
+
+/// the repl for `-D`
+pub fn run_debugger(po: &Options) -> Result<(), String> {
+    let program = match read_to_string(&po.path) {
+        Ok(s) => s,
+        Err(_) => return Err(format!("unable to read file {}", po.path))
+    };
+    // the terminal belongs to the repl, so the program gets its input from `in`
+    let mut d = Debugger::new(&program, ProcessorBuilder::new()
+        .input(io::empty())
+        .bus(Bus::from_options(po)?)
+        .isa(Isa::from_options(po)?.unwrap_or_default()))?;
+    d.processor.fault_policy = po.fault_policy;
+
+    let stdin = io::stdin();
+    let mut out = io::stdout();
+    let _ = d.command("l", &mut out);
+    loop {
+        print!("(avc) ");
+        let _ = out.flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break, // eof
+            Ok(_) => {}
+            Err(e) => return Err(format!("unable to read command: {}", e))
+        }
+        match d.command(&line, &mut out) {
+            Ok(Action::Quit) => break,
+            Ok(Action::Continue) => {}
+            Err(e) => return Err(format!("unable to write output: {}", e))
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "        lda #0xf0
+        ssp
+        jsr PRINT
+        dat \"hi\"
+        dat 0
+        hlt
+
+PRINT:  ppa
+        sta PRDAT
+        ppa
+        sta PRDAT+1
+INT:    lda (PRDAT),x
+        jez RET
+        put
+        inc
+        jmp INT
+RET:    txa
+        swp
+        lda PRDAT+1
+        add
+        psa
+        lda PRDAT
+        psa
+        rts
+PRDAT:  dat 0
+        dat 0";
+
+    fn run(d: &mut Debugger, cmd: &str) -> String {
+        let mut out = Vec::new();
+        d.command(cmd, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn session() {
+        let mut d = Debugger::new(PROGRAM, ProcessorBuilder::new().output(io::sink()).input(io::empty())).unwrap();
+        assert_eq!(run(&mut d, "fin"), "error: no frame to finish, the stack has no return address on it\n");
+        assert_eq!(run(&mut d, "s 2"), "0x0003 (line 3): jsr PRINT\n");
+        assert_eq!(run(&mut d, "b PRINT"), "breakpoint 0 at 0x000a <PRINT> (line 8)\n");
+        assert_eq!(run(&mut d, "c"), "breakpoint 0 at 0x000a <PRINT> (line 8)\n0x000a <PRINT> (line 8): ppa\n");
+        assert_eq!(run(&mut d, "d 0"), "deleted breakpoint 0\n");
+        assert_eq!(run(&mut d, "fin"), "0x0008 (line 5): nop\n");
+        assert_eq!(run(&mut d, "x PRDAT 2"), format!("0x0029: 00 06{}  |..|  ; PRDAT at 0x0029\n", " ".repeat(42)));
+        assert_eq!(run(&mut d, "x/1 PRDAT+1"), format!("0x002a: 06{}  |.|\n", " ".repeat(45)));
+        assert_eq!(run(&mut d, "x/0 PRDAT"), "error: can't examine 0 bytes from 0x0029\n");
+        assert_eq!(d.processor.x, 2);
+        run(&mut d, "set x 0x10");
+        run(&mut d, "set PRDAT+1 7");
+        assert_eq!((d.processor.x, d.processor.memory[0x2a]), (0x10, 7));
+        assert!(run(&mut d, "l").contains("=>    5         dat 0"));
+        run(&mut d, "s");
+        assert_eq!(run(&mut d, "s"), "\nhalted\n0x000a <PRINT> (line 8): ppa\n");
+    }
+
+    #[test]
+    fn interrupted() {
+        // the interrupt is taken with the pc on the brk, so the handler runs before the brk does
+        let src = "
+        lda #0xf0
+        ssp
+        sei
+STOP:   brk
+        hlt
+        org 0x0100
+HANDLER: get
+        rti
+        org 0xfffe
+        dat 0x01
+        dat 0x00";
+        let mut d = Debugger::new(src, ProcessorBuilder::new().output(io::sink()).input(io::Cursor::new(b"a".to_vec()))).unwrap();
+        assert_eq!(run(&mut d, "c"), "brk at 0x0004 <STOP> (line 5)\n0x0005 <STOP+1> (line 6): hlt\n");
+        assert_eq!(d.processor.a, b'a');
+    }
+
+    #[test]
+    fn next_steps_over_jsr() {
+        let mut d = Debugger::new(PROGRAM, ProcessorBuilder::new().output(io::sink()).input(io::empty())).unwrap();
+        run(&mut d, "s 2");
+        run(&mut d, "n");
+        assert_eq!(d.processor.program_counter, 8);
+        assert_eq!(run(&mut d, "dis 3"), "   0x0000  lda #0xf0\n   0x0002  ssp\n   0x0003  jsr PRINT\n\
+            => 0x0008  nop\n   0x0009  hlt\nPRINT:\n   0x000a  ppa\n");
+    }
+
+    #[test]
+    fn conditions_and_watchpoints() {
+        let mut d = Debugger::new(PROGRAM, ProcessorBuilder::new().output(io::sink()).input(io::empty())).unwrap();
+        run(&mut d, "b INT if hits == 3");
+        assert_eq!(run(&mut d, "watch PRDAT PRDAT+1"), "watchpoint 0 on 0x0029 <PRDAT> to 0x002a <PRDAT+1>\n");
+        assert_eq!(run(&mut d, "c"), "watchpoint 0: wrote 0x00 at 0x0029 <PRDAT> from 0x000b <PRINT+1> (line 9)\n\
+            0x000e <PRINT+4> (line 10): ppa\n");
+        run(&mut d, "unwatch 0");
+        assert_eq!(run(&mut d, "c"), "breakpoint 0 at 0x0012 <INT> (line 12)\n0x0012 <INT> (line 12): lda (PRDAT),x\n");
+        assert_eq!(d.processor.x, 2);
+        assert_eq!(run(&mut d, "i"), "breakpoint 0: 0x0012 <INT> (line 12) if hits == 3, hit 3 times\n");
+        assert_eq!(run(&mut d, "b INT when"), "error: usage: break WHERE [if COND]\n");
+    }
+
+    #[test]
+    fn reverse() {
+        let mut d = Debugger::new(PROGRAM, ProcessorBuilder::new().output(io::sink()).input(io::empty())).unwrap();
+        run(&mut d, "b INT");
+        run(&mut d, "c");
+        run(&mut d, "c");
+        assert_eq!(d.processor.x, 1);
+        assert_eq!(run(&mut d, "who PRDAT+1"), "0x002a <PRDAT+1> was last written by 0x000f <PRINT+5> (line 11), \
+            0x00 -> 0x06, 6 instructions ago\n");
+        assert_eq!(run(&mut d, "rc"), "breakpoint 0 at 0x0012 <INT> (line 12)\n0x0012 <INT> (line 12): lda (PRDAT),x\n");
+        assert_eq!(d.processor.x, 0);
+        assert_eq!(run(&mut d, "rs"), "0x000f <PRINT+5> (line 11): sta 0x002a\n");
+        assert_eq!(d.processor.memory[0x2a], 0);
+        run(&mut d, "watch PRDAT");
+        assert_eq!(run(&mut d, "rc"), "watchpoint 0\n0x000b <PRINT+1> (line 9): sta PRDAT\n");
+        assert_eq!(run(&mut d, "rc"), "reached the start of the recorded history\n0x0000 (line 1): lda #0xf0\n");
+        assert_eq!(run(&mut d, "who PRDAT"), "0x0029 <PRDAT> hasn't been written in the recorded history\n");
+    }
+}
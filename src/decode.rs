@@ -0,0 +1,262 @@
+#[cfg(feature = "jit")]
+use std::cell::{Cell, OnceCell};
+use std::rc::Rc;
+
+use crate::isa::Isa;
+#[cfg(feature = "jit")]
+use crate::jit::Native;
+use crate::processor::cycle_cost;
+use crate::utils::bytes_to_16;
+
+/// an instruction with everything the processor would otherwise work out from memory each time it ran
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Decoded {
+    pub op: u8,
+    pub operand: u16, // the address of a wide instruction, or the byte `lda #` loads
+    pub len: u8,
+    pub cost: u8,
+    pub legal: bool // whether the op is in the isa it was decoded for
+}
+
+pub fn decode(memory: &[u8; 65536], pc: u16, isa: Isa) -> Decoded {
+    let op = memory[pc as usize];
+    let byte = |i: u16| memory[pc.wrapping_add(i) as usize];
+    let (len, operand) = if op & 0b1000_0000 != 0 {
+        (3, bytes_to_16(byte(1), byte(2)))
+    }
+    else if op == 25 { // lda const
+        (2, byte(1) as u16)
+    }
+    else {
+        (1, 0)
+    };
+    Decoded { op, operand, len, cost: cycle_cost(op) as u8, legal: isa.has_opcode(op) }
+}
+
+impl Decoded {
+    /// whether the next instruction might not be the one after this in memory
+    fn ends_block(&self) -> bool {
+        if !self.legal {
+            return true
+        }
+        if self.op & 0b1000_0000 != 0 {
+            return !matches!(self.op & 0b0010_0111, 0b000|0b001) // anything but lda and sta
+        }
+        matches!(self.op, 1|23|24|34) // hlt, brk, rts, rti
+    }
+}
+
+/// straight line code, decoded
+pub(crate) struct Block {
+    pub instrs: Vec<Decoded>,
+    size: u16, // in bytes
+    #[cfg(feature = "jit")]
+    pub runs: Cell<u32>,
+    #[cfg(feature = "jit")]
+    pub native: OnceCell<Option<Native>> // set once the block is hot, to `None` if it couldn't be translated
+}
+
+/// longer blocks are split, so a block never covers more than 2 pages
+const MAX_BLOCK: usize = 32;
+
+/// blocks by their start address. blocks are dropped when the program writes to a byte in them, so self
+/// modifying code works, and memory changed from outside the processor means starting again with `clear`
+#[derive(Default)]
+pub(crate) struct BlockCache {
+    blocks: Vec<Option<Rc<Block>>>, // 65536 of them, once anything's been cached
+    pages: Vec<Vec<u16>>, // the starts of the blocks on each page
+    covered: Vec<u8>, // how many blocks each byte is in, so writes to data next to code are quick to rule out
+    pub dirty: bool // a block was dropped since this was last cleared
+}
+
+impl BlockCache {
+    pub fn clear(&mut self) {
+        if self.blocks.is_empty() {
+            self.blocks = vec![None; 65536];
+            self.pages = vec![Vec::new(); 256];
+            self.covered = vec![0; 65536]
+        }
+        for p in self.pages.iter_mut() {
+            for start in p.drain(..) {
+                self.blocks[start as usize] = None
+            }
+        }
+        self.covered.fill(0);
+        self.dirty = false
+    }
+
+    /// `clear` has to have been called first
+    pub fn get(&mut self, memory: &[u8; 65536], pc: u16, isa: Isa) -> Rc<Block> {
+        if let Some(b) = &self.blocks[pc as usize] {
+            return b.clone()
+        }
+        let mut instrs = Vec::new();
+        let mut addr = pc;
+        loop {
+            let d = decode(memory, addr, isa);
+            instrs.push(d);
+            addr = addr.wrapping_add(d.len as u16);
+            if d.ends_block() || instrs.len() == MAX_BLOCK {
+                break
+            }
+        }
+        let size = addr.wrapping_sub(pc);
+        for i in 0..size {
+            self.covered[pc.wrapping_add(i) as usize] += 1
+        }
+        let first_page = pc >> 8;
+        let last_page = addr.wrapping_sub(1) >> 8;
+        self.pages[first_page as usize].push(pc);
+        if last_page != first_page {
+            self.pages[last_page as usize].push(pc)
+        }
+        let block = Rc::new(Block {
+            instrs,
+            size,
+            #[cfg(feature = "jit")]
+            runs: Cell::new(0),
+            #[cfg(feature = "jit")]
+            native: OnceCell::new()
+        });
+        self.blocks[pc as usize] = Some(block.clone());
+        block
+    }
+
+    /// called on every write to ram
+    pub fn written(&mut self, addr: u16) {
+        match self.covered.get(addr as usize) {
+            Some(n) if *n > 0 => {}
+            _ => return
+        }
+        let page = addr as usize >> 8;
+        let mut i = 0;
+        while i < self.pages[page].len() {
+            let start = self.pages[page][i];
+            let size = match &self.blocks[start as usize] {
+                Some(b) => b.size,
+                None => unreachable!()
+            };
+            if addr.wrapping_sub(start) >= size {
+                i += 1;
+                continue
+            }
+            self.blocks[start as usize] = None;
+            for j in 0..size {
+                self.covered[start.wrapping_add(j) as usize] -= 1
+            }
+            for p in [start >> 8, start.wrapping_add(size - 1) >> 8] { // off both pages, if it's on 2
+                if let Some(k) = self.pages[p as usize].iter().position(|s| *s == start) {
+                    self.pages[p as usize].swap_remove(k);
+                }
+            }
+            self.dirty = true
+        }
+    }
+
+    /// `covered` as native code sees it
+    #[cfg(feature = "jit")]
+    pub fn covered(&self) -> &[u8] {
+        &self.covered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::SharedBuffer;
+    use std::io::{self, Cursor};
+    use crate::assembler::assemble_with_isa;
+    use crate::bus::Bus;
+    use crate::processor::{FaultPolicy, Processor, ProcessorBuilder, StepOutcome};
+    use crate::snapshot::Snapshot;
+
+    /// runs a program one step at a time and from the block cache, which have to end up in the same place
+    fn compare(src: &str, isa: Isa, input: &[u8], setup: impl Fn(&mut Processor)) -> (Snapshot, Vec<u8>) {
+        let (prog, _) = assemble_with_isa(src, isa).unwrap();
+        let run = |fast: bool| {
+            let out = SharedBuffer::default();
+            let mut p = ProcessorBuilder::new()
+                .memory(&prog)
+                .isa(isa)
+                .output(out.clone())
+                .input(Cursor::new(input.to_vec()))
+                .bus(Bus::from_config("0xff00 0xff00 rng 3").unwrap())
+                .build();
+            setup(&mut p);
+            while !p.halted {
+                let _ = if fast { p.run_fast() } else { p.step() };
+            }
+            let output = out.contents();
+            (p.snapshot(), output)
+        };
+        let stepped = run(false);
+        assert_eq!(run(true), stepped, "{}", src);
+        stepped
+    }
+
+    #[test]
+    fn same_as_stepping() {
+        let (s, out) = compare(include_str!("../avc_examples/hello_world.avc"), Isa::V1, b"", |_| {});
+        assert_eq!(out, b"hello world!\n");
+        assert!(s.fault.is_none());
+
+        // the store changes the instruction after it, which is already in the block
+        let (s, _) = compare("
+        lda #1
+        sta PATCH
+PATCH:  nop
+        lda #0x2a
+        hlt", Isa::V1, b"", |_| {});
+        assert_eq!(s.a, 1);
+
+        // input interrupts in the middle of a block, and illegal opcodes trapping
+        let (s, out) = compare("
+        lda #0x01
+        sta 0xfffe
+        sta 0xfffc
+        lda #0x10
+        sta 0xfffd
+        lda #0
+        swp
+        lda #0x10
+        ssp
+        sei
+LOOP:   inc
+        lda 0xff00
+        lda #0x09
+        sta ILLEGAL
+        txa
+ILLEGAL: nop
+        jnz LOOP
+        hlt
+        org 0x0100
+        get
+        put
+        rti
+        org 0x0110
+FAULT:  rti", Isa::V2, b"abc", |p| p.fault_policy = FaultPolicy::Trap);
+        assert_eq!(out, b"abc");
+        assert!(s.halted);
+
+        // the cycle budget runs out at the same instruction
+        let (s, _) = compare("
+LOOP:   inc
+        nop
+        jmp LOOP", Isa::V1, b"", |p| p.cycle_budget = Some(1003));
+        assert_eq!(s.cycles, 1005);
+    }
+
+    #[test]
+    fn stops() {
+        let (prog, _) = assemble_with_isa("nop\nbrk\nnop\nhlt", Isa::V1).unwrap();
+        let mut p = ProcessorBuilder::new().memory(&prog).output(io::sink()).input(io::empty()).build();
+        assert_eq!(p.run_fast(), Ok(StepOutcome::Break));
+        assert_eq!(p.program_counter, 2);
+        assert_eq!(p.run_fast(), Ok(StepOutcome::Halted));
+
+        // breakpoints still stop it, since it steps when they're set
+        let mut p = ProcessorBuilder::new().memory(&prog).output(io::sink()).input(io::empty()).build();
+        p.breakpoints.push(crate::processor::Breakpoint::new(1, None));
+        assert_eq!(p.run_fast(), Ok(StepOutcome::Breakpoint(0)));
+    }
+}
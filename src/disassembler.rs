@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+
+use crate::assembler::closest_label;
+use crate::isa::Isa;
+use crate::utils::bytes_to_16;
+
+/// mnemonics for single width instructions, indexed by opcode. `None` is unassigned
+const SINGLE_WIDTH: [Option<&str>; 36] = [
+    Some("nop"), Some("hlt"), Some("swp"), Some("tab"), Some("tax"), Some("txa"), Some("inc"), Some("dec"),
+    Some("add"), None, Some("sub"), Some("sbc"), Some("lsr"), Some("lsl"), Some("clc"), Some("sec"),
+    Some("put"), Some("psa"), Some("ppa"), Some("gst"), Some("sst"), Some("ssp"), Some("gsp"), Some("brk"),
+    Some("rts"), Some("lda"), Some("get"), Some("not"), Some("and"), Some("ior"), Some("xor"), Some("gbf"),
+    Some("sei"), Some("cli"), Some("rti"), Some("cmp")
+];
+const WIDE: [Option<&str>; 8] = [
+    Some("lda"), Some("sta"), Some("jmp"), Some("jsr"), Some("jez"), Some("jgt"), None, None
+];
+/// wide instructions with bit 5 set
+const FLAG_BRANCHES: [Option<&str>; 8] = [
+    Some("jnz"), Some("jcs"), Some("jcc"), Some("jmi"), Some("jpl"), Some("jvs"), Some("jvc"), None
+];
+
+/// disassembles the instruction at `addr`, returning its assembly and its length in bytes.
+/// bytes that aren't an instruction in `isa` come out as `dat`. addresses are named after labels if `labels` has one
+pub fn disassemble(memory: &[u8], addr: usize, isa: Isa, labels: Option<&HashMap<String, usize>>) -> (String, usize) {
+    let byte = |i: usize| memory.get(addr + i).copied().unwrap_or(0);
+    let instr = byte(0);
+    if !isa.has_opcode(instr) {
+        return (format!("dat 0x{:0>2x}", instr), 1)
+    }
+
+    if instr & 0b1000_0000 == 0 {
+        let name = match SINGLE_WIDTH.get(instr as usize) {
+            Some(Some(n)) => *n,
+            _ => return (format!("dat 0x{:0>2x}", instr), 1)
+        };
+        if instr == 25 { // lda const
+            return (format!("lda #0x{:0>2x}", byte(1)), 2)
+        }
+        return (String::from(name), 1)
+    }
+
+    let table = match instr & 0b0110_0000 {
+        0 => &WIDE,
+        0b0010_0000 => &FLAG_BRANCHES,
+        _ => return (format!("dat 0x{:0>2x}", instr), 1)
+    };
+    let name = match table[(instr & 0b111) as usize] {
+        Some(n) => n,
+        None => return (format!("dat 0x{:0>2x}", instr), 1)
+    };
+    let target = bytes_to_16(byte(1), byte(2)) as usize;
+    let mut operand = match labels.and_then(|l| closest_label(l, target)) {
+        Some((label, at)) if at == target => label.clone(),
+        _ => format!("0x{:0>4x}", target)
+    };
+    if instr & 0b0001_0000 != 0 {
+        operand = format!("({})", operand)
+    }
+    if instr & 0b1000 != 0 {
+        operand.push_str(",x")
+    }
+    (format!("{} {}", name, operand), 3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble_with_isa;
+    use crate::isa::Isa;
+
+    #[test]
+    fn round_trip() {
+        let src = "START:  lda #0x10\n        sta (0x0300),x\n        jsr START\n        sei\n        rti\n        jmi (START),x\n        jvc 0x1234\n        gst\n        sub\n        sbc\n        cmp\n        hlt";
+        let (prog, debug) = assemble_with_isa(src, Isa::V2).unwrap();
+        let mut addr = 0;
+        let mut out = Vec::new();
+        while addr < prog.len() {
+            let (text, len) = disassemble(&prog, addr, Isa::V2, Some(&debug.labels));
+            out.push(text);
+            addr += len
+        }
+        assert_eq!(out, ["lda #0x10", "sta (0x0300),x", "jsr START", "sei", "rti", "jmi (START),x", "jvc 0x1234", "gst", "sub", "sbc", "cmp", "hlt"]);
+        assert_eq!(disassemble(&[9], 0, Isa::V2, None), (String::from("dat 0x09"), 1));
+        assert_eq!(disassemble(&[35], 0, Isa::V2, None), (String::from("cmp"), 1));
+        assert_eq!(disassemble(&[0b1000_0110, 0, 0], 0, Isa::V2, None), (String::from("dat 0x86"), 1));
+        assert_eq!(disassemble(&[0b1010_0111, 0, 0], 0, Isa::V2, None), (String::from("dat 0xa7"), 1));
+        assert_eq!(disassemble(&[0b1100_0000, 0, 0], 0, Isa::V2, None), (String::from("dat 0xc0"), 1));
+
+        // v1 doesn't have sub, sbc, cmp or the flag branches
+        for op in [10, 11, 35, 0b1010_0000] {
+            assert_eq!(disassemble(&[op, 0, 0], 0, Isa::V1, None), (format!("dat 0x{:0>2x}", op), 1))
+        }
+        assert_eq!(disassemble(&[8], 0, Isa::V1, None), (String::from("add"), 1));
+    }
+
+    #[test]
+    fn shared_labels() {
+        // the first alphabetically, whatever order the hash map has them in
+        let labels = HashMap::from([(String::from("B"), 0x10), (String::from("A"), 0x10), (String::from("C"), 0x10)]);
+        assert_eq!(disassemble(&[0b1000_0010, 0, 0x10], 0, Isa::V1, Some(&labels)).0, "jmp A");
+        assert_eq!(disassemble(&[0b1000_0010, 0, 0x11], 0, Isa::V1, Some(&labels)).0, "jmp 0x0011");
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+
+use crate::assembler::DebugInfo;
+use crate::isa::Isa;
+use crate::processor::Processor;
+use crate::utils::ByteReader;
+
+/// the start of an executable, followed by a format version. it starts with `isa::HEADER_MAGIC` too, so
+/// loaders have to check for an executable before reading an isa header, or `X` is taken for the revision
+pub const MAGIC: &[u8; 4] = b"AVCX";
+pub const VERSION: u8 = 1;
+
+/// a program with everything needed to start it, as opposed to a raw image, which is loaded at
+/// address 0 and started there with `sp` at 0
+#[derive(Clone, Debug, PartialEq)]
+pub struct Executable {
+    pub isa: Isa,
+    pub entry: u16,
+    pub stack_pointer: u16,
+    pub segments: Vec<Segment>, // memory outside them starts as 0
+    pub symbols: Option<HashMap<String, usize>> // labels, unless they were stripped
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub start: u16,
+    pub bytes: Vec<u8>
+}
+
+/// whether a binary is an executable rather than a raw image
+pub fn is_executable(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+impl Executable {
+    /// packages an assembled program. only the bytes the source placed are kept, so the gaps `org`
+    /// leaves aren't stored
+    pub fn from_assembly(assembly: &[u8], debug: &DebugInfo, isa: Isa, symbols: bool) -> Executable {
+        Executable {
+            isa,
+            entry: debug.entry.unwrap_or(0),
+            stack_pointer: debug.stack_pointer.unwrap_or(0),
+            segments: debug.segments.iter()
+                .map(|(start, end)| Segment { start: *start as u16, bytes: assembly[*start..*end].to_vec() })
+                .collect(),
+            symbols: if symbols { Some(debug.labels.clone()) } else { None }
+        }
+    }
+
+    /// the file format is big endian: the magic, version and isa revision, the entry point and stack pointer,
+    /// a count of segments, each with its start, length and bytes, then whether there's a symbol table and
+    /// its count of symbols, each with its address and name
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut ret = MAGIC.to_vec();
+        ret.push(VERSION);
+        ret.push(self.isa.revision());
+        ret.extend_from_slice(&self.entry.to_be_bytes());
+        ret.extend_from_slice(&self.stack_pointer.to_be_bytes());
+        ret.extend_from_slice(&(self.segments.len() as u16).to_be_bytes());
+        for s in &self.segments {
+            ret.extend_from_slice(&s.start.to_be_bytes());
+            ret.extend_from_slice(&(s.bytes.len() as u32).to_be_bytes());
+            ret.extend_from_slice(&s.bytes)
+        }
+        match &self.symbols {
+            None => ret.push(0),
+            Some(symbols) => {
+                ret.push(1);
+                // sorted, so the same program always makes the same file
+                let mut sorted = symbols.iter().collect::<Vec<(&String, &usize)>>();
+                sorted.sort_by_key(|(name, addr)| (**addr, *name));
+                ret.extend_from_slice(&(sorted.len() as u16).to_be_bytes());
+                for (name, addr) in sorted {
+                    ret.extend_from_slice(&(*addr as u16).to_be_bytes());
+                    ret.extend_from_slice(&(name.len() as u16).to_be_bytes());
+                    ret.extend_from_slice(name.as_bytes())
+                }
+            }
+        }
+        ret
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Executable, String> {
+        let mut r = ByteReader::new(bytes, "executable");
+        if r.take(4)? != MAGIC {
+            return Err(String::from("not an executable"))
+        }
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(format!("executable is version {}, but only version {} is supported", version, VERSION))
+        }
+        let revision = r.u8()?;
+        let isa = match Isa::from_revision(revision) {
+            Some(isa) => isa,
+            None => return Err(format!("executable is for isa revision {}, which this emulator doesn't know", revision))
+        };
+        let entry = r.u16()?;
+        let stack_pointer = r.u16()?;
+        let mut segments = Vec::new();
+        for _ in 0..r.u16()? {
+            let start = r.u16()?;
+            let bytes = r.bytes()?.to_vec();
+            if start as usize + bytes.len() > 65536 {
+                return Err(format!("segment at 0x{:0>4x} runs past the end of memory", start))
+            }
+            segments.push(Segment { start, bytes })
+        }
+        let symbols = match r.u8()? {
+            0 => None,
+            _ => {
+                let mut symbols = HashMap::new();
+                for _ in 0..r.u16()? {
+                    let addr = r.u16()? as usize;
+                    let len = r.u16()? as usize;
+                    let name = String::from_utf8_lossy(r.take(len)?).into_owned();
+                    symbols.insert(name, addr);
+                }
+                Some(symbols)
+            }
+        };
+        r.finish()?;
+        Ok(Executable { isa, entry, stack_pointer, segments, symbols })
+    }
+}
+
+impl Processor {
+    /// replaces memory with the executable's segments, and gets ready to start at its entry point
+    pub fn load_executable(&mut self, exe: &Executable) {
+        self.memory = [0; 65536];
+        for s in &exe.segments {
+            let start = s.start as usize;
+            self.memory[start..start + s.bytes.len()].copy_from_slice(&s.bytes)
+        }
+        self.isa = exe.isa;
+        self.program_counter = exe.entry;
+        self.stack_pointer = exe.stack_pointer;
+        self.symbols = exe.symbols.clone()
+    }
+    /// starts an assembled program where its `entry` and `stack` directives say to
+    pub fn start_at_entry(&mut self, debug: &DebugInfo) {
+        self.program_counter = debug.entry.unwrap_or(0);
+        self.stack_pointer = debug.stack_pointer.unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::assembler::assemble_with_isa;
+    use crate::processor::ProcessorBuilder;
+    use crate::utils::Options;
+
+    #[test]
+    fn round_trip() {
+        let src = "
+        stack 0x8000
+        entry MAIN
+        dat \"hi\"
+        org 0x0400
+MAIN:   jsr PRINT
+        hlt
+PRINT:  lda 0x0001
+        sta 0x0300
+        rts";
+        let (prog, debug) = assemble_with_isa(src, Isa::V2).unwrap();
+        assert_eq!(debug.segments, vec![(0, 2), (0x0400, 0x040b)]);
+        let exe = Executable::from_assembly(&prog, &debug, Isa::V2, true);
+        assert_eq!((exe.entry, exe.stack_pointer), (0x0400, 0x8000));
+        let bytes = exe.to_bytes();
+        assert!(is_executable(&bytes));
+        // the gap between the segments isn't stored
+        assert!(bytes.len() < 0x0400);
+        assert_eq!(Executable::from_bytes(&bytes), Ok(exe.clone()));
+
+        let mut p = ProcessorBuilder::new().output(io::sink()).input(io::empty()).build();
+        p.memory[0x0200] = 0xaa; // left over from before
+        p.load_executable(&exe);
+        assert_eq!((p.isa, p.program_counter, p.stack_pointer), (Isa::V2, 0x0400, 0x8000));
+        assert_eq!(p.memory[0x0200], 0);
+        while !p.halted {
+            p.step().unwrap();
+        }
+        assert_eq!(p.memory[0x0300], b'i');
+        // the return address went on the stack at 0x8000, not over the string at 0
+        assert_eq!(&p.memory[0x8000..0x8002], &[0x03, 0x04]);
+
+        let stripped = Executable::from_assembly(&prog, &debug, Isa::V2, false);
+        assert_eq!(Executable::from_bytes(&stripped.to_bytes()).unwrap().symbols, None);
+        assert!(Executable::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut overrun = Executable { segments: vec![Segment { start: 0xffff, bytes: vec![1, 2] }], ..exe }.to_bytes();
+        assert!(Executable::from_bytes(&overrun).unwrap_err().contains("past the end"));
+        overrun[4] = 2;
+        assert!(Executable::from_bytes(&overrun).unwrap_err().contains("version 2"));
+    }
+
+    #[test]
+    fn load() {
+        let (prog, debug) = assemble_with_isa("stack 0x8000\nentry MAIN\nnop\nMAIN: hlt", Isa::V2).unwrap();
+        let path = std::env::temp_dir().join(format!("avc-executable-test-{}", std::process::id()));
+        std::fs::write(&path, Executable::from_assembly(&prog, &debug, Isa::V2, true).to_bytes()).unwrap();
+        let po = Options { path: path.to_str().unwrap().to_string(), ..Options::default() };
+        let p = Processor::new(&po);
+        std::fs::remove_file(&path).unwrap();
+        let p = p.unwrap();
+        assert_eq!((p.isa, p.program_counter, p.stack_pointer), (Isa::V2, 1, 0x8000));
+    }
+}
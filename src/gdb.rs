@@ -0,0 +1,535 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use crate::processor::{Breakpoint, Fault, Processor, StepOutcome, Watchpoint};
+use crate::utils::{bytes_to_16, u16_to_bytes};
+
+/// registers are a, b, x, s, sp and pc, in that order. sp and pc are big endian like the rest of the machine
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.avc.core">
+    <reg name="a" bitsize="8" type="uint8"/>
+    <reg name="b" bitsize="8" type="uint8"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="s" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>"#;
+
+/// the biggest packet we tell the client we'll take, and the most we'll send back
+const PACKET_SIZE: usize = 0x1000;
+
+/// how many instructions to run between checks for a ctrl-c from the client
+const INTERRUPT_CHECK_INTERVAL: u32 = 4096;
+
+/// a connection to a client. it has to be switchable to non blocking so `continue` can check for ctrl-c
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// waits for one client on `addr` and serves it until it detaches or kills the program.
+/// addresses with a `/` in them are unix sockets, anything else is `host:port`
+pub fn serve(p: &mut Processor, addr: &str) -> Result<(), String> {
+    if addr.contains('/') {
+        #[cfg(unix)]
+        {
+            // a socket left over from last time can go, but anything else there is someone else's
+            if let Ok(m) = std::fs::symlink_metadata(addr) {
+                if !m.file_type().is_socket() {
+                    return Err(format!("unable to listen on {}: address in use by something that isn't a socket", addr))
+                }
+                let _ = std::fs::remove_file(addr);
+            }
+            let listener = match UnixListener::bind(addr) {
+                Ok(l) => l,
+                Err(e) => return Err(format!("unable to listen on {}: {}", addr, e))
+            };
+            eprintln!("waiting for gdb on {}", addr);
+            let result = match listener.accept() {
+                Ok((stream, _)) => Stub::new(stream).run(p),
+                Err(e) => Err(format!("unable to accept connection: {}", e))
+            };
+            let _ = std::fs::remove_file(addr);
+            return result
+        }
+        #[cfg(not(unix))]
+        return Err(String::from("unix sockets aren't supported on this platform"))
+    }
+    let listener = match TcpListener::bind(addr) {
+        Ok(l) => l,
+        Err(e) => return Err(format!("unable to listen on {}: {}", addr, e))
+    };
+    eprintln!("waiting for gdb on {}", addr);
+    serve_tcp(p, &listener)
+}
+
+/// serves the next client to connect to `listener`
+pub fn serve_tcp(p: &mut Processor, listener: &TcpListener) -> Result<(), String> {
+    match listener.accept() {
+        Ok((stream, _)) => {
+            let _ = stream.set_nodelay(true);
+            Stub::new(stream).run(p)
+        }
+        Err(e) => Err(format!("unable to accept connection: {}", e))
+    }
+}
+
+/// the remote serial protocol side of a connection
+pub struct Stub<C: Connection> {
+    conn: C,
+    ack: bool, // turned off by QStartNoAckMode
+    pending: Vec<u8> // bytes read past the end of the last packet
+}
+
+impl<C: Connection> Stub<C> {
+    pub fn new(conn: C) -> Stub<C> {
+        Stub { conn, ack: true, pending: Vec::new() }
+    }
+
+    pub fn run(&mut self, p: &mut Processor) -> Result<(), String> {
+        loop {
+            let packet = match self.read_packet() {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return Ok(()), // disconnected
+                Err(e) => return Err(format!("gdb connection failed: {}", e))
+            };
+            if packet == "k" { // no reply, the program's just gone
+                return Ok(())
+            }
+            let reply = match self.handle(p, &packet) {
+                Ok(r) => r,
+                Err(e) => return Err(format!("gdb connection failed: {}", e))
+            };
+            if let Err(e) = self.send(&reply) {
+                return Err(format!("gdb connection failed: {}", e))
+            }
+            if packet == "QStartNoAckMode" {
+                self.ack = false
+            }
+            if packet.starts_with('D') {
+                return Ok(())
+            }
+        }
+    }
+
+    fn handle(&mut self, p: &mut Processor, packet: &str) -> io::Result<String> {
+        let (cmd, args) = packet.split_at(packet.chars().next().map(|c| c.len_utf8()).unwrap_or(0));
+        let reply = match cmd {
+            "?" => stop_reply(p, None),
+            "g" => {
+                let (sh, sl) = u16_to_bytes(p.stack_pointer);
+                let (ph, pl) = u16_to_bytes(p.program_counter);
+                hex(&[p.a, p.b, p.x, p.status, sh, sl, ph, pl])
+            }
+            "G" => match unhex(args) {
+                Some(b) if b.len() == 8 => {
+                    (p.a, p.b, p.x, p.status) = (b[0], b[1], b[2], b[3]);
+                    p.stack_pointer = bytes_to_16(b[4], b[5]);
+                    p.program_counter = bytes_to_16(b[6], b[7]);
+                    String::from("OK")
+                }
+                _ => String::from("E01")
+            },
+            "p" => match u16::from_str_radix(args, 16) {
+                Ok(0) => hex(&[p.a]),
+                Ok(1) => hex(&[p.b]),
+                Ok(2) => hex(&[p.x]),
+                Ok(3) => hex(&[p.status]),
+                Ok(4) => hex(&p.stack_pointer.to_be_bytes()),
+                Ok(5) => hex(&p.program_counter.to_be_bytes()),
+                _ => String::from("E01")
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(r, v)| Some((u16::from_str_radix(r, 16).ok()?, unhex(v)?)));
+                match parsed {
+                    Some((0, v)) if v.len() == 1 => p.a = v[0],
+                    Some((1, v)) if v.len() == 1 => p.b = v[0],
+                    Some((2, v)) if v.len() == 1 => p.x = v[0],
+                    Some((3, v)) if v.len() == 1 => p.status = v[0],
+                    Some((4, v)) if v.len() == 2 => p.stack_pointer = bytes_to_16(v[0], v[1]),
+                    Some((5, v)) if v.len() == 2 => p.program_counter = bytes_to_16(v[0], v[1]),
+                    _ => return Ok(String::from("E01"))
+                }
+                String::from("OK")
+            }
+            // memory goes straight to ram, so looking at it doesn't set off devices. a reply may be
+            // shorter than asked for, and the client reads the rest with another packet
+            "m" => match parse_range(args) {
+                Some((addr, len)) => {
+                    let len = len.min(PACKET_SIZE / 2);
+                    let bytes = (0..len).map(|i| p.memory[addr.wrapping_add(i as u16) as usize]).collect::<Vec<u8>>();
+                    hex(&bytes)
+                }
+                None => String::from("E01")
+            },
+            "M" => {
+                let parsed = args.split_once(':').and_then(|(r, d)| Some((parse_range(r)?, unhex(d)?)));
+                match parsed {
+                    Some(((addr, len), data)) if data.len() == len => {
+                        for (i, b) in data.iter().enumerate() {
+                            p.memory[addr.wrapping_add(i as u16) as usize] = *b
+                        }
+                        String::from("OK")
+                    }
+                    _ => String::from("E01")
+                }
+            }
+            "s" => {
+                if let Some(addr) = parse_resume_addr(args) {
+                    p.program_counter = addr
+                }
+                let outcome = p.step();
+                stop_reply(p, Some(outcome))
+            }
+            "c" => {
+                if let Some(addr) = parse_resume_addr(args) {
+                    p.program_counter = addr
+                }
+                self.resume(p)?
+            }
+            "Z"|"z" => self.set_point(p, cmd == "Z", args),
+            "H"|"T" => String::from("OK"), // there's only one thread
+            "D" => String::from("OK"),
+            "q"|"Q" => self.query(packet),
+            _ => String::new() // unsupported
+        };
+        Ok(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE)
+        }
+        if let Some(rest) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_range(rest) {
+                Some((offset, len)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = (offset + len).min(TARGET_XML.len());
+                    let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                    format!("{}{}", more, &TARGET_XML[offset..end])
+                }
+                None => String::from("E01")
+            }
+        }
+        match packet {
+            "QStartNoAckMode" => String::from("OK"), // run turns acks off once this is sent
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            _ => String::new()
+        }
+    }
+
+    /// z0/z1 are breakpoints, z2/z3/z4 are write/read/access watchpoints
+    fn set_point(&mut self, p: &mut Processor, insert: bool, args: &str) -> String {
+        let mut parts = args.split(',');
+        let kind = parts.next();
+        let addr = parts.next().and_then(|a| u16::from_str_radix(a, 16).ok());
+        let len = parts.next().and_then(|l| u16::from_str_radix(l, 16).ok()).unwrap_or(1).max(1);
+        let addr = match addr {
+            Some(a) => a,
+            None => return String::from("E01")
+        };
+        let end = addr.saturating_add(len - 1);
+        let (read, write) = match kind {
+            Some("0")|Some("1") => {
+                if insert {
+                    if !p.breakpoints.iter().any(|b| b.address == addr && b.condition.is_none()) {
+                        p.breakpoints.push(Breakpoint::new(addr, None))
+                    }
+                }
+                else {
+                    p.breakpoints.retain(|b| b.address != addr || b.condition.is_some())
+                }
+                return String::from("OK")
+            }
+            Some("2") => (false, true),
+            Some("3") => (true, false),
+            Some("4") => (true, true),
+            _ => return String::new()
+        };
+        if insert {
+            p.watchpoints.push(Watchpoint::new(addr, end, read, write))
+        }
+        else {
+            p.watchpoints.retain(|w| !(w.start == addr && w.end == end && w.read == read && w.write == write))
+        }
+        String::from("OK")
+    }
+
+    /// runs until something stops the processor or the client sends a ctrl-c
+    fn resume(&mut self, p: &mut Processor) -> io::Result<String> {
+        let mut count: u32 = 0;
+        loop {
+            let outcome = p.step();
+            match outcome {
+                Ok(StepOutcome::Continue)|Ok(StepOutcome::Trapped(_)) => {}
+                _ => return Ok(stop_reply(p, Some(outcome)))
+            }
+            count += 1;
+            if count.is_multiple_of(INTERRUPT_CHECK_INTERVAL) && self.interrupted()? {
+                return Ok(String::from("S02"))
+            }
+        }
+    }
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.conn.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.conn.read(&mut byte);
+        self.conn.set_nonblocking(false)?;
+        match result {
+            Ok(1) if byte[0] == 3 => Ok(true),
+            Ok(1) => {
+                self.pending.push(byte[0]);
+                Ok(false)
+            }
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if !self.pending.is_empty() {
+            return Ok(Some(self.pending.remove(0)))
+        }
+        let mut byte = [0];
+        loop {
+            match self.conn.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e)
+            }
+        }
+    }
+    /// skips acks and stray ctrl-cs, and asks for a resend if the checksum is wrong
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                Some(b'$') => {}
+                Some(_) => continue,
+                None => return Ok(None)
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                    None => return Ok(None)
+                }
+            }
+            let mut sum = [0; 2];
+            for s in sum.iter_mut() {
+                match self.read_byte()? {
+                    Some(b) => *s = b,
+                    None => return Ok(None)
+                }
+            }
+            let expected = std::str::from_utf8(&sum).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+            if self.ack {
+                if expected != Some(checksum(&data)) {
+                    self.conn.write_all(b"-")?;
+                    continue
+                }
+                self.conn.write_all(b"+")?;
+            }
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+        }
+    }
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:0>2x}", data, checksum(data.as_bytes()));
+        self.conn.write_all(packet.as_bytes())?;
+        self.conn.flush()
+    }
+}
+
+/// `S05` is a sigtrap, for anything the debugger should stop for. a halt is an exit, with a status of 0
+/// or the fault's code
+fn stop_reply(p: &Processor, outcome: Option<Result<StepOutcome, Fault>>) -> String {
+    match outcome {
+        Some(Ok(StepOutcome::Watchpoint(hit))) => {
+            let w = &p.watchpoints[hit.index];
+            let kind = match (w.read, w.write) {
+                (true, true) => "awatch",
+                (true, false) => "rwatch",
+                _ => "watch"
+            };
+            format!("T05{}:{:x};", kind, hit.address)
+        }
+        Some(Err(f)) => format!("W{:0>2x}", f.code()),
+        _ if p.halted => match &p.fault {
+            Some(f) => format!("W{:0>2x}", f.code()),
+            None => String::from("W00")
+        },
+        _ => String::from("S05")
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:0>2x}", b)).collect()
+}
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+/// `ADDR,LEN` in hex. lengths past the whole address space are refused, so a bad client can't
+/// make us allocate without bound
+fn parse_range(s: &str) -> Option<(u16, usize)> {
+    let (addr, len) = s.split_once(',')?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    if len > 0x10000 {
+        return None
+    }
+    Some((u16::from_str_radix(addr, 16).ok()?, len))
+}
+fn parse_resume_addr(s: &str) -> Option<u16> {
+    if s.is_empty() {
+        None
+    }
+    else {
+        u16::from_str_radix(s, 16).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::assembler::assemble_with_debug;
+    use crate::processor::ProcessorBuilder;
+
+    /// a scripted client, sending a packet and checking the reply at each step
+    struct Client(TcpStream);
+    impl Client {
+        fn ask(&mut self, packet: &str) -> String {
+            write!(self.0, "${}#{:0>2x}", packet, checksum(packet.as_bytes())).unwrap();
+            let mut reply = Vec::new();
+            let mut byte = [0];
+            loop {
+                self.0.read_exact(&mut byte).unwrap();
+                match byte[0] {
+                    b'+' if reply.is_empty() => {}
+                    b'#' => break,
+                    b => reply.push(b)
+                }
+            }
+            let mut sum = [0; 2];
+            self.0.read_exact(&mut sum).unwrap();
+            self.0.write_all(b"+").unwrap();
+            let reply = String::from_utf8(reply).unwrap();
+            assert_eq!(u8::from_str_radix(std::str::from_utf8(&sum).unwrap(), 16).unwrap(), checksum(&reply.as_bytes()[1..]));
+            String::from(&reply[1..]) // past the $
+        }
+    }
+
+    #[test]
+    fn session() {
+        let src = "
+        lda #0xf0
+        ssp
+LOOP:   lda #1
+        sta 0x0300
+        inc
+        txa
+        tab
+        lda #5
+        jgt LOOP
+        hlt";
+        let (prog, debug) = assemble_with_debug(src).unwrap();
+        let mut p = ProcessorBuilder::new().memory(&prog).output(io::sink()).input(io::empty()).build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let loop_addr = debug.labels["LOOP"];
+
+        let client = thread::spawn(move || {
+            let mut c = Client(TcpStream::connect(addr).unwrap());
+            c.0.set_nodelay(true).unwrap();
+            assert!(c.ask("qSupported:swbreak+").contains("PacketSize=1000;"));
+            assert_eq!(c.ask("?"), "S05");
+            assert_eq!(c.ask("g"), "0000000000000000");
+            assert_eq!(c.ask("s"), "S05");
+            assert_eq!(c.ask("p5"), "0002");
+            assert_eq!(c.ask("P0=2a"), "OK");
+            assert_eq!(c.ask("p0"), "2a");
+            assert_eq!(c.ask("m0,3"), "19f015");
+            assert_eq!(c.ask(&format!("Z0,{:x},1", loop_addr)), "OK");
+            assert_eq!(c.ask("c"), "S05");
+            assert_eq!(c.ask("p5"), format!("{:0>4x}", loop_addr));
+            assert_eq!(c.ask(&format!("z0,{:x},1", loop_addr)), "OK");
+            assert_eq!(c.ask("Z2,300,1"), "OK");
+            assert_eq!(c.ask("c"), "T05watch:300;");
+            assert_eq!(c.ask("m300,1"), "01");
+            assert_eq!(c.ask("z2,300,1"), "OK");
+            assert_eq!(c.ask("M300,2:abcd"), "OK");
+            assert_eq!(c.ask("m300,2"), "abcd");
+            assert_eq!(c.ask("m0,ffffffffffff"), "E01");
+            assert_eq!(c.ask("m0,10000").len(), 0x1000);
+            assert_eq!(c.ask("c"), "W00");
+            assert_eq!(c.ask("p2"), "05");
+            assert!(c.ask("qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+            assert_eq!(c.ask("vMustReplyEmpty"), "");
+            c.0.write_all(b"$k#6b").unwrap();
+        });
+        serve_tcp(&mut p, &listener).unwrap();
+        client.join().unwrap();
+        assert!(p.halted);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket() {
+        let path = std::env::temp_dir().join(format!("avc-gdb-test-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut p = ProcessorBuilder::new().memory(&[1]).output(io::sink()).input(io::empty()).build();
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(serve(&mut p, &path).unwrap_err().contains("address in use"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_file(&path).unwrap();
+        drop(UnixListener::bind(&path).unwrap()); // a stale socket, which serve replaces
+        let client_path = path.clone();
+        let client = thread::spawn(move || {
+            let mut stream = loop { // wait for serve to bind
+                match UnixStream::connect(&client_path) {
+                    Ok(s) => break s,
+                    Err(_) => thread::sleep(std::time::Duration::from_millis(10))
+                }
+            };
+            stream.write_all(b"$c#63").unwrap();
+            let mut reply = [0; 8];
+            stream.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply, b"+$W00#b7");
+            stream.write_all(b"+$D#44").unwrap();
+            let mut reply = [0; 7];
+            stream.read_exact(&mut reply).unwrap();
+            assert_eq!(&reply, b"+$OK#9a");
+        });
+        serve(&mut p, &path).unwrap();
+        client.join().unwrap();
+        assert!(p.halted);
+        assert!(!std::path::Path::new(&path).exists());
+    }
+}
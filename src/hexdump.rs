@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::fs::write;
+
+use crate::processor::Processor;
+
+const ROW: usize = 16;
+
+/// memory from `start` to `end`, both included, as rows of hex and ascii. rows with labels in them say which, like
+/// `0x0029: 00 06 ... |..|  ; PRDAT at 0x0029`
+pub fn hexdump(memory: &[u8], start: u16, end: u16, labels: Option<&HashMap<String, usize>>) -> String {
+    let mut sorted = match labels {
+        Some(l) => l.iter().map(|(name, addr)| (*addr, name.as_str())).collect::<Vec<(usize, &str)>>(),
+        None => Vec::new()
+    };
+    sorted.sort();
+    let end = end as usize;
+    let mut ret = String::new();
+    let mut row = start as usize;
+    while row <= end {
+        let row_end = (row + ROW - 1).min(end);
+        let bytes = &memory[row..=row_end];
+        ret.push_str(&format!("0x{:0>4x}:", row));
+        for b in bytes {
+            ret.push_str(&format!(" {:0>2x}", b))
+        }
+        ret.push_str(&" ".repeat((ROW - bytes.len()) * 3));
+        let ascii = bytes.iter()
+            .map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' })
+            .collect::<String>();
+        ret.push_str(&format!("  |{}|", ascii));
+        let here = sorted.iter()
+            .filter(|(addr, _)| *addr >= row && *addr <= row_end)
+            .map(|(addr, name)| format!("{} at 0x{:0>4x}", name, addr))
+            .collect::<Vec<String>>();
+        if !here.is_empty() {
+            ret.push_str(&format!("  ; {}", here.join(", ")))
+        }
+        ret.push('\n');
+        row += ROW
+    }
+    ret
+}
+
+/// writes the bytes in each range, both ends included, one after another, for diffing with other tools
+pub fn write_regions(memory: &[u8], ranges: &[(u16, u16)], path: &str) -> Result<(), String> {
+    let mut bytes = Vec::new();
+    for (start, end) in ranges {
+        bytes.extend_from_slice(&memory[*start as usize..=*end as usize])
+    }
+    match write(path, bytes) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("unable to write memory to {}: {}", path, e))
+    }
+}
+
+impl Processor {
+    /// a hexdump of memory, labelled with the program's symbols if it has them
+    pub fn hexdump(&self, start: u16, end: u16) -> String {
+        hexdump(&self.memory, start, end, self.symbols.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::assembler::assemble_with_debug;
+    use crate::processor::ProcessorBuilder;
+    use crate::utils::Options;
+
+    #[test]
+    fn rows() {
+        let mut memory = vec![0; 65536];
+        memory[0xfff0..0xfff6].copy_from_slice(b"hi\nall");
+        let labels = HashMap::from([(String::from("MSG"), 0xfff0), (String::from("END"), 0xffff), (String::from("NL"), 0xfff2)]);
+        assert_eq!(hexdump(&memory, 0xffee, 0xffff, Some(&labels)), concat!(
+            "0xffee: 00 00 68 69 0a 61 6c 6c 00 00 00 00 00 00 00 00  |..hi.all........|  ; MSG at 0xfff0, NL at 0xfff2\n",
+            "0xfffe: 00 00                                            |..|  ; END at 0xffff\n"
+        ));
+        assert_eq!(hexdump(&memory, 0xfff0, 0xfff0, None), format!("0xfff0: 68{}  |h|\n", " ".repeat(45)));
+    }
+
+    #[test]
+    fn dump_on_brk() {
+        let src = "
+        lda #1
+        sta 0x0300
+        brk
+        lda #2
+        sta 0x0301
+        hlt";
+        let (prog, _) = assemble_with_debug(src).unwrap();
+        let mut p = ProcessorBuilder::new().memory(&prog).output(io::sink()).input(io::empty()).build();
+        let path = std::env::temp_dir().join(format!("avc-hexdump-test-{}", std::process::id()));
+        let po = Options {
+            dump_mem: String::from("0x0300:0x0301, 0:1"),
+            dump_mem_file: path.to_str().unwrap().to_string(),
+            ..Options::default()
+        };
+        let mut out = Vec::new();
+        p.run_dumping(&po, &mut out).unwrap();
+        let written = std::fs::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        let row = |addr: &str, bytes: &str| format!("{}: {}{}  |..|\n", addr, bytes, " ".repeat(42));
+        // at the brk, then when it halts
+        assert_eq!(String::from_utf8(out).unwrap(), [row("0x0300", "01 00"), row("0x0000", "19 01"),
+            row("0x0300", "01 02"), row("0x0000", "19 01")].concat());
+        assert_eq!(written.unwrap(), vec![1, 2, 0x19, 1]);
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::VecDeque;
+
+use crate::processor::{Fault, Processor};
+use crate::replay::Chunk;
+
+/// an undo log of the last few steps, so a processor can be run backwards.
+/// only the processor's own state is recorded. output that's already gone and device state can't be taken back
+pub struct History {
+    steps: VecDeque<Step>,
+    limit: usize,
+    current: Option<Step> // the step being executed
+}
+
+/// everything needed to put one step back
+struct Step {
+    pc: u16,
+    a: u8,
+    b: u8,
+    x: u8,
+    status: u8,
+    sp: u16,
+    halted: bool,
+    cycles: u64,
+    fault: Option<Fault>,
+    fault_pc: u16,
+    consumed_input: Option<u8>, // a byte taken off the front of the input buffer by `get`
+    arrived_input: Vec<Chunk>, // input polled into the end of the buffer
+    writes: Vec<MemWrite>
+}
+
+/// a write to ram, found by `History::last_write`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemWrite {
+    pub address: u16,
+    pub old: u8,
+    pub new: u8
+}
+
+/// who wrote an address and when
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteRecord {
+    pub pc: u16, // the instruction that wrote it
+    pub cycles: u64, // the cycle count when the instruction started
+    pub steps_ago: usize,
+    pub write: MemWrite
+}
+
+impl History {
+    /// keeps at most `limit` steps, forgetting the oldest
+    pub fn new(limit: usize) -> History {
+        History { steps: VecDeque::new(), limit, current: None }
+    }
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub(crate) fn begin(&mut self, p: &Processor) {
+        self.current = Some(Step {
+            pc: p.program_counter,
+            a: p.a,
+            b: p.b,
+            x: p.x,
+            status: p.status,
+            sp: p.stack_pointer,
+            halted: p.halted,
+            cycles: p.cycles,
+            fault: p.fault.clone(),
+            fault_pc: p.fault_pc,
+            consumed_input: None,
+            arrived_input: Vec::new(),
+            writes: Vec::new()
+        })
+    }
+    pub(crate) fn record_write(&mut self, address: u16, old: u8, new: u8) {
+        if let Some(s) = &mut self.current {
+            s.writes.push(MemWrite { address, old, new })
+        }
+    }
+    pub(crate) fn record_input(&mut self, byte: u8) {
+        if let Some(s) = &mut self.current {
+            s.consumed_input = Some(byte)
+        }
+    }
+    pub(crate) fn record_arrival(&mut self, cycle: u64, bytes: &[u8]) {
+        if let Some(s) = &mut self.current {
+            s.arrived_input.push(Chunk { cycle, bytes: bytes.to_vec() })
+        }
+    }
+    /// forgets every recorded step, for when the state they lead back to is gone
+    pub(crate) fn clear(&mut self) {
+        self.steps.clear();
+        self.current = None
+    }
+    /// forgets the step being executed, for steps that stopped before running anything
+    pub(crate) fn discard(&mut self) {
+        self.current = None
+    }
+    pub(crate) fn commit(&mut self) {
+        if let Some(s) = self.current.take() {
+            if self.limit == 0 {
+                return
+            }
+            if self.steps.len() == self.limit {
+                self.steps.pop_front();
+            }
+            self.steps.push_back(s)
+        }
+    }
+
+    /// the most recent recorded write to `address`
+    pub fn last_write(&self, address: u16) -> Option<WriteRecord> {
+        for (i, s) in self.steps.iter().rev().enumerate() {
+            if let Some(w) = s.writes.iter().rev().find(|w| w.address == address) {
+                return Some(WriteRecord { pc: s.pc, cycles: s.cycles, steps_ago: i + 1, write: *w })
+            }
+        }
+        None
+    }
+}
+
+impl Processor {
+    /// starts recording an undo log of up to `limit` steps, or stops if `limit` is 0
+    pub fn record_history(&mut self, limit: usize) {
+        self.history = if limit == 0 { None } else { Some(History::new(limit)) }
+    }
+
+    /// undoes the last recorded step, returning the writes it undid, or `None` at the start of the history
+    pub fn step_back(&mut self) -> Option<Vec<MemWrite>> {
+        let s = self.history.as_mut()?.steps.pop_back()?;
+        for w in s.writes.iter().rev() {
+            self.memory[w.address as usize] = w.old
+        }
+        if let Some(b) = s.consumed_input {
+            self.get_buffer.insert(0, b)
+        }
+        // input that arrived during the step is taken back out of the buffer, and arrives again when the step is
+        // run again, so it's read the same way
+        let arrived: usize = s.arrived_input.iter().map(|c| c.bytes.len()).sum();
+        self.get_buffer.truncate(self.get_buffer.len() - arrived);
+        for c in s.arrived_input.into_iter().rev() {
+            self.rewound_input.push_front(c)
+        }
+        self.program_counter = s.pc;
+        self.a = s.a;
+        self.b = s.b;
+        self.x = s.x;
+        self.status = s.status;
+        self.stack_pointer = s.sp;
+        self.halted = s.halted;
+        self.cycles = s.cycles;
+        self.fault = s.fault;
+        self.fault_pc = s.fault_pc;
+        Some(s.writes)
+    }
+
+    /// delivers input that was stepped back over when the cycle it first arrived at comes round again. a poll
+    /// that gets any of it, or that comes while there's still some to come, doesn't look for anything newer
+    pub(crate) fn reread_input(&mut self) -> bool {
+        let mut delivered = false;
+        while let Some(c) = self.rewound_input.front() {
+            if c.cycle > self.cycles {
+                return true
+            }
+            let c = self.rewound_input.pop_front().unwrap();
+            self.get_buffer.extend_from_slice(&c.bytes);
+            if let Some(h) = &mut self.history {
+                h.record_arrival(c.cycle, &c.bytes)
+            }
+            delivered = true
+        }
+        delivered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Cursor};
+    use crate::assembler::assemble_with_debug;
+    use crate::processor::ProcessorBuilder;
+    use crate::replay::InputLog;
+
+    #[test]
+    fn run_backwards() {
+        let src = "
+        lda #0xf0
+        ssp
+        get
+        sta 0x0300
+        psa
+        jsr SUB
+        hlt
+SUB:    lda #7
+        sta 0x0300
+        rts";
+        let (prog, _) = assemble_with_debug(src).unwrap();
+        let mut p = ProcessorBuilder::new().memory(&prog).output(io::sink()).input(Cursor::new(b"q".to_vec())).build();
+        p.record_history(100);
+        let mut states = Vec::new();
+        while !p.halted {
+            states.push((p.program_counter, p.a, p.stack_pointer, p.cycles, p.memory[0x300], p.memory[0xf000]));
+            p.step().unwrap();
+        }
+        assert_eq!(p.history.as_ref().unwrap().len(), states.len());
+
+        let w = p.history.as_ref().unwrap().last_write(0x300).unwrap();
+        assert_eq!((w.write, w.steps_ago), (MemWrite { address: 0x300, old: b'q', new: 7 }, 3));
+        assert_eq!(p.history.as_ref().unwrap().last_write(0xf002).map(|w| w.write.new), Some(0)); // return address hi
+
+        while let Some(state) = states.pop() {
+            assert!(p.step_back().is_some());
+            assert_eq!(state, (p.program_counter, p.a, p.stack_pointer, p.cycles, p.memory[0x300], p.memory[0xf000]));
+        }
+        assert!(p.step_back().is_none());
+        assert!(!p.halted);
+        // the input is back to be read again
+        assert!(p.get_buffer.is_empty());
+        for _ in 0..3 {
+            p.step().unwrap();
+        }
+        assert_eq!(p.a, b'q');
+    }
+
+    #[test]
+    fn input() {
+        // polls until input turns up a few steps in, then reads it
+        let src = "
+LOOP:   inc
+        gbf
+        jez LOOP
+        get
+        hlt";
+        let (prog, _) = assemble_with_debug(src).unwrap();
+        let late = || Chunk { cycle: 9, bytes: b"hi".to_vec() };
+        for replaying in [true, false] {
+            let mut p = ProcessorBuilder::new().memory(&prog).output(io::sink()).input(io::empty()).build();
+            if replaying {
+                p.input_log = Some(InputLog::Replay(VecDeque::from([late()])))
+            }
+            else {
+                p.rewound_input.push_back(late()) // stands in for someone typing, since it only comes once
+            }
+            p.record_history(100);
+            let mut states = Vec::new();
+            while !p.halted {
+                states.push((p.program_counter, p.a, p.x, p.get_buffer.clone()));
+                p.step().unwrap();
+            }
+            assert_eq!((p.a, p.x), (b'h', 2));
+            for state in states.iter().rev() {
+                p.step_back().unwrap();
+                assert_eq!(*state, (p.program_counter, p.a, p.x, p.get_buffer.clone()));
+            }
+            // going forward again, the input turns up at the same point and is read the same way
+            for state in &states {
+                assert_eq!(*state, (p.program_counter, p.a, p.x, p.get_buffer.clone()));
+                p.step().unwrap();
+            }
+            assert_eq!((p.a, p.x, p.get_buffer.as_slice()), (b'h', 2, &b"i"[..]));
+        }
+    }
+
+    #[test]
+    fn limit() {
+        let mut p = ProcessorBuilder::new().output(io::sink()).input(io::empty()).build();
+        p.record_history(3);
+        for _ in 0..10 {
+            p.step().unwrap();
+        }
+        assert_eq!(p.history.as_ref().unwrap().len(), 3);
+        for _ in 0..3 {
+            p.step_back().unwrap();
+        }
+        assert_eq!(p.program_counter, 7);
+        assert!(p.step_back().is_none());
+    }
+
+    #[test]
+    fn out_of_cycles() {
+        let mut p = ProcessorBuilder::new().output(io::sink()).input(io::empty()).build();
+        p.record_history(10);
+        p.cycle_budget = Some(2);
+        p.step().unwrap();
+        p.step().unwrap();
+        assert!(p.step().is_err());
+        assert_eq!(p.history.as_ref().unwrap().len(), 2);
+    }
+}
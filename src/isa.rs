@@ -0,0 +1,120 @@
+use std::fmt;
+
+use crate::utils::Options;
+
+/// the start of a binary with a header, followed by the isa revision. no program starts with these bytes,
+/// since `A` isn't an opcode
+pub const HEADER_MAGIC: &[u8; 3] = b"AVC";
+
+/// revisions of the instruction set. binaries keep running on the revision they were assembled for
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Isa {
+    #[default]
+    V1, // the original
+    V2 // adds sub, sbc, cmp, the flag branches, and the negative and overflow flags
+}
+
+impl Isa {
+    pub fn from_name(s: &str) -> Result<Isa, String> {
+        match s {
+            "v1" => Ok(Isa::V1),
+            "v2" => Ok(Isa::V2),
+            _ => Err(format!("unknown isa {}, expected v1 or v2", s))
+        }
+    }
+    /// `--isa`, if it was given
+    pub fn from_options(po: &Options) -> Result<Option<Isa>, String> {
+        if po.isa.is_empty() {
+            Ok(None)
+        }
+        else {
+            Isa::from_name(&po.isa).map(Some)
+        }
+    }
+    pub fn revision(&self) -> u8 {
+        match self {
+            Isa::V1 => 1,
+            Isa::V2 => 2
+        }
+    }
+    pub fn from_revision(r: u8) -> Option<Isa> {
+        match r {
+            1 => Some(Isa::V1),
+            2 => Some(Isa::V2),
+            _ => None
+        }
+    }
+
+    /// whether an opcode is an instruction in this revision
+    pub fn has_opcode(&self, op: u8) -> bool {
+        if op & 0b1000_0000 == 0 {
+            return match self {
+                Isa::V1 => op <= 34 && !(9..=11).contains(&op),
+                Isa::V2 => op <= 35 && op != 9
+            }
+        }
+        match (self, op & 0b0110_0000) {
+            (_, 0) => op & 0b111 <= 0b101,
+            (Isa::V2, 0b0010_0000) => op & 0b111 <= 0b110,
+            _ => false
+        }
+    }
+
+    pub fn header(&self) -> Vec<u8> {
+        let mut ret = HEADER_MAGIC.to_vec();
+        ret.push(self.revision());
+        ret
+    }
+}
+
+impl fmt::Display for Isa {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v{}", self.revision())
+    }
+}
+
+/// splits a binary into the isa named by its header, if it has one, and the program
+pub fn read_header(bytes: &[u8]) -> Result<(Option<Isa>, &[u8]), String> {
+    if bytes.len() < 4 || &bytes[..3] != HEADER_MAGIC {
+        return Ok((None, bytes))
+    }
+    match Isa::from_revision(bytes[3]) {
+        Some(isa) => Ok((Some(isa), &bytes[4..])),
+        None => Err(format!("binary is for isa revision {}, which this emulator doesn't know", bytes[3]))
+    }
+}
+
+/// the isa to run a binary with. a header and `--isa` have to agree
+pub fn choose(header: Option<Isa>, option: Option<Isa>) -> Result<Isa, String> {
+    match (header, option) {
+        (Some(h), Some(o)) if h != o => Err(format!("the binary is for isa {}, but --isa {} was given", h, o)),
+        (Some(h), _) => Ok(h),
+        (None, Some(o)) => Ok(o),
+        (None, None) => Ok(Isa::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles() {
+        assert!(Isa::V1.has_opcode(8) && Isa::V1.has_opcode(34) && Isa::V1.has_opcode(0b1001_1101));
+        for op in [9, 10, 11, 35, 0b1010_0000, 0b1000_0110] {
+            assert!(!Isa::V1.has_opcode(op), "{}", op)
+        }
+        assert!(Isa::V2.has_opcode(10) && Isa::V2.has_opcode(35) && Isa::V2.has_opcode(0b1011_1110));
+        for op in [9, 36, 0b1010_0111, 0b1100_0000] {
+            assert!(!Isa::V2.has_opcode(op), "{}", op)
+        }
+
+        let mut bin = Isa::V2.header();
+        bin.push(1);
+        assert_eq!(read_header(&bin), Ok((Some(Isa::V2), &[1u8][..])));
+        assert_eq!(read_header(&[1, 2]), Ok((None, &[1u8, 2][..])));
+        assert!(read_header(b"AVC\x07").is_err());
+        assert!(choose(Some(Isa::V2), Some(Isa::V1)).is_err());
+        assert_eq!(choose(None, None), Ok(Isa::V1));
+    }
+}
@@ -0,0 +1,666 @@
+//! translates hot blocks into x86-64 code. only instructions that can't fault, poll input or touch devices are
+//! translated, and a block is only run natively when nothing else could happen between its instructions: no
+//! devices, interrupts disabled, and enough of the cycle budget left. everything else is left to the interpreter
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("the jit feature only supports x86-64 linux");
+
+use std::mem::offset_of;
+use std::ptr;
+
+use crate::decode::{Block, Decoded};
+use crate::isa::Isa;
+use crate::processor::{CARRY, INTERRUPT_ENABLE, NEGATIVE, OVERFLOW, ZERO};
+
+/// how many times a block runs before it's translated
+pub const HOT: u32 = 16;
+
+/// the registers, passed in and out of native code
+#[repr(C)]
+#[derive(Default, Debug)]
+pub(crate) struct Regs {
+    pub a: u8,
+    pub b: u8,
+    pub x: u8,
+    pub status: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub written: u16, // the address of a store over code, which ended the block early
+    pub wrote: u8
+}
+
+// where Regs' fields are
+const REG_A: i32 = offset_of!(Regs, a) as i32;
+const REG_B: i32 = offset_of!(Regs, b) as i32;
+const REG_X: i32 = offset_of!(Regs, x) as i32;
+const REG_STATUS: i32 = offset_of!(Regs, status) as i32;
+const REG_SP: i32 = offset_of!(Regs, sp) as i32;
+const REG_PC: i32 = offset_of!(Regs, pc) as i32;
+const REG_WRITTEN: i32 = offset_of!(Regs, written) as i32;
+const REG_WROTE: i32 = offset_of!(Regs, wrote) as i32;
+
+// host registers. rdi, rsi and rdx are the arguments: the Regs, memory and which bytes have code in them.
+// the avc registers live in r8 to r11 while a block runs, and rax, rcx and rbx are scratch
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const A: u8 = 8;
+const B: u8 = 9;
+const X: u8 = 10;
+const S: u8 = 11;
+
+// condition codes, for setcc and jcc
+const CC_O: u8 = 0x0;
+const CC_NC: u8 = 0x3;
+const CC_Z: u8 = 0x4;
+const CC_NZ: u8 = 0x5;
+const CC_A: u8 = 0x7;
+
+/// a block's translated instructions, which are the block's up to its first one that can't be translated
+pub(crate) struct Native {
+    code: *mut libc::c_void,
+    len: usize,
+    pub instrs: usize,
+    pub costs: Vec<u64> // the cycles the first n instructions take, for every n up to `instrs`
+}
+
+impl Native {
+    /// `None` if the block doesn't start with anything that can be translated, or memory for the code can't be had
+    pub fn compile(instrs: &[Decoded], pc: u16, isa: Isa) -> Option<Native> {
+        let n = instrs.iter().take_while(|d| translatable(d)).count();
+        if n == 0 {
+            return None
+        }
+        let instrs = &instrs[..n];
+        let mut asm = Asm::default();
+        asm.byte(0x53); // push rbx
+        for (reg, offset) in [(A, REG_A), (B, REG_B), (X, REG_X), (S, REG_STATUS)] {
+            asm.mem(&[0x0f, 0xb6], reg, Mem::Disp(RDI, offset)) // movzx
+        }
+        let mut addr = pc;
+        for (i, d) in instrs.iter().enumerate() {
+            let next = addr.wrapping_add(d.len as u16);
+            // the zero and negative flags don't need working out if the next instruction sets them again
+            let flags_live = match instrs.get(i + 1) {
+                Some(after) => reads_zn(after.op) || zn_written(after.op, isa) & zn_written(d.op, isa) != zn_written(d.op, isa),
+                None => true
+            };
+            asm.instruction(d, isa, next, i + 1, flags_live);
+            addr = next
+        }
+        asm.exit(Pc::Imm(addr), n);
+
+        // SAFETY: a fresh private mapping, written before it's made executable and never written after
+        let code = unsafe {
+            let code = libc::mmap(ptr::null_mut(), asm.code.len(), libc::PROT_READ|libc::PROT_WRITE,
+                libc::MAP_PRIVATE|libc::MAP_ANONYMOUS, -1, 0);
+            if code == libc::MAP_FAILED {
+                return None
+            }
+            ptr::copy_nonoverlapping(asm.code.as_ptr(), code as *mut u8, asm.code.len());
+            if libc::mprotect(code, asm.code.len(), libc::PROT_READ|libc::PROT_EXEC) != 0 {
+                libc::munmap(code, asm.code.len());
+                return None
+            }
+            code
+        };
+        let mut costs = vec![0];
+        for d in instrs {
+            costs.push(costs[costs.len() - 1] + d.cost as u64)
+        }
+        Some(Native { code, len: asm.code.len(), instrs: n, costs })
+    }
+
+    /// runs the block from its start, returning how many instructions ran. the pc is left at the next
+    /// instruction, and `covered` has a nonzero byte for each address with code on it
+    pub fn run(&self, regs: &mut Regs, memory: &mut [u8; 65536], covered: &[u8]) -> usize {
+        assert_eq!(covered.len(), 65536);
+        // SAFETY: the code only touches `regs`, and memory and `covered` through 16 bit indexes, and saves the
+        // callee saved registers it uses
+        unsafe {
+            let f: unsafe extern "C" fn(*mut Regs, *mut u8, *const u8) -> u32 = std::mem::transmute(self.code);
+            f(regs, memory.as_mut_ptr(), covered.as_ptr()) as usize
+        }
+    }
+}
+
+impl Drop for Native {
+    fn drop(&mut self) {
+        // SAFETY: mapped in compile, and nothing can be running it once it's dropped
+        unsafe {
+            libc::munmap(self.code, self.len);
+        }
+    }
+}
+
+impl Block {
+    /// the native code for a block starting at `pc`, once it's run often enough to be worth translating
+    pub(crate) fn native(&self, pc: u16, isa: Isa) -> Option<&Native> {
+        if let Some(n) = self.native.get() {
+            return n.as_ref()
+        }
+        let runs = self.runs.get() + 1;
+        self.runs.set(runs);
+        if runs < HOT {
+            return None
+        }
+        self.native.get_or_init(|| Native::compile(&self.instrs, pc, isa)).as_ref()
+    }
+}
+
+/// instructions that don't fault, do i/o, use the stack or enable interrupts
+fn translatable(d: &Decoded) -> bool {
+    if !d.legal {
+        return false
+    }
+    if d.op & 0b1000_0000 != 0 {
+        return d.op & 0b0010_0000 != 0 || d.op & 0b111 != 0b011 // anything but jsr
+    }
+    matches!(d.op, 0|2..=8|10..=15|19|21|22|25|27..=30|33|35)
+}
+
+/// the host register an instruction sets the zero and negative flags from, if it sets them. v1 sets the zero
+/// flag from `a` after everything
+fn zn_source(op: u8, isa: Isa) -> Option<u8> {
+    if isa == Isa::V1 {
+        return Some(A)
+    }
+    if op & 0b1000_0000 != 0 {
+        return if op & 0b1110_0111 == 0b1000_0000 { Some(A) } else { None } // lda
+    }
+    match op {
+        3 => Some(B), // tab
+        4|6|7 => Some(X), // tax, inc, dec
+        2|5|8|10..=13|25|27..=30 => Some(A),
+        35 => Some(RAX), // cmp, which leaves its result in al
+        _ => None
+    }
+}
+
+fn zn_written(op: u8, isa: Isa) -> u8 {
+    match (zn_source(op, isa), isa) {
+        (None, _) => 0,
+        (Some(_), Isa::V1) => ZERO,
+        (Some(_), Isa::V2) => ZERO|NEGATIVE
+    }
+}
+
+/// jnz, jez, jmi, jpl and gst
+fn reads_zn(op: u8) -> bool {
+    if op & 0b1010_0000 == 0b1010_0000 {
+        return matches!(op & 0b111, 0b000|0b011|0b100)
+    }
+    op & 0b1110_0111 == 0b1000_0100 || op == 19
+}
+
+/// where the pc goes when native code exits
+#[derive(Clone, Copy)]
+enum Pc {
+    Imm(u16),
+    Ax // the effective address, in ax
+}
+
+#[derive(Clone, Copy)]
+enum Mem {
+    Disp(u8, i32), // [base + disp32]
+    Index(u8, u8) // [base + index]
+}
+
+#[derive(Default)]
+struct Asm {
+    code: Vec<u8>
+}
+
+impl Asm {
+    fn byte(&mut self, b: u8) {
+        self.code.push(b)
+    }
+    fn bytes(&mut self, b: &[u8]) {
+        self.code.extend_from_slice(b)
+    }
+    /// always emitted, so r8 to r15 work and the byte registers are al, cl, dl and bl
+    fn rex(&mut self, reg: u8, index: u8, base: u8) {
+        self.byte(0x40 | (reg >> 3) << 2 | (index >> 3) << 1 | base >> 3)
+    }
+    /// `op r/m, reg` between registers
+    fn rr(&mut self, op: u8, reg: u8, rm: u8) {
+        self.rex(reg, 0, rm);
+        self.bytes(&[op, 0xc0 | (reg & 7) << 3 | rm & 7])
+    }
+    /// an instruction with an opcode extension instead of a register, like `not` or `inc`
+    fn ext(&mut self, op: &[u8], ext: u8, rm: u8) {
+        self.rex(0, 0, rm);
+        self.bytes(op);
+        self.byte(0xc0 | ext << 3 | rm & 7)
+    }
+    /// `op r/m8, imm8`, where `ext` is 0 for add, 1 for or, 4 for and and 7 for cmp
+    fn ri(&mut self, ext: u8, rm: u8, imm: u8) {
+        self.ext(&[0x80], ext, rm);
+        self.byte(imm)
+    }
+    fn test_imm(&mut self, rm: u8, imm: u8) {
+        self.ext(&[0xf6], 0, rm);
+        self.byte(imm)
+    }
+    fn setcc(&mut self, cc: u8, rm: u8) {
+        self.ext(&[0x0f, 0x90 | cc], 0, rm)
+    }
+    fn mem(&mut self, op: &[u8], reg: u8, m: Mem) {
+        match m {
+            Mem::Disp(base, disp) => {
+                self.rex(reg, 0, base);
+                self.bytes(op);
+                self.byte(0x80 | (reg & 7) << 3 | base & 7);
+                self.bytes(&disp.to_le_bytes())
+            }
+            Mem::Index(base, index) => {
+                self.rex(reg, index, base);
+                self.bytes(op);
+                self.byte((reg & 7) << 3 | 0b100);
+                self.byte((index & 7) << 3 | base & 7)
+            }
+        }
+    }
+    fn mov_eax(&mut self, imm: u32) {
+        self.byte(0xb8);
+        self.bytes(&imm.to_le_bytes())
+    }
+    /// sets the carry flag from the avc carry
+    fn carry_in(&mut self) {
+        self.ext(&[0x0f, 0xba], 4, S); // bt r11d, 0
+        self.byte(0)
+    }
+    /// the avc carry from cl, and the overflow from al if `overflow`
+    fn carry_out(&mut self, overflow: bool) {
+        self.ri(4, S, !(CARRY | if overflow { OVERFLOW } else { 0 }));
+        self.rr(0x08, RCX, S);
+        if overflow {
+            self.ext(&[0xc0], 4, RAX); // shl al, 6
+            self.byte(6);
+            self.rr(0x08, RAX, S)
+        }
+    }
+    fn zero_negative(&mut self, v: u8, isa: Isa) {
+        self.ri(4, S, if isa == Isa::V1 { !ZERO } else { !(ZERO|NEGATIVE) });
+        self.rr(0x84, v, v); // test
+        self.setcc(CC_Z, RBX);
+        self.ext(&[0xd0], 4, RBX); // shl bl, 1
+        self.rr(0x08, RBX, S);
+        if isa == Isa::V2 {
+            self.rr(0x88, v, RBX);
+            self.ri(4, RBX, NEGATIVE);
+            self.rr(0x08, RBX, S)
+        }
+    }
+    /// a jcc to be patched, returning where its offset goes
+    fn jcc(&mut self, cc: u8) -> usize {
+        self.bytes(&[0x0f, 0x80 | cc, 0, 0, 0, 0]);
+        self.code.len() - 4
+    }
+    fn patch(&mut self, at: usize) {
+        let offset = (self.code.len() - at - 4) as i32;
+        self.code[at..at + 4].copy_from_slice(&offset.to_le_bytes())
+    }
+    /// writes the registers back and returns `n`, the number of instructions that ran
+    fn exit(&mut self, pc: Pc, n: usize) {
+        for (reg, offset) in [(A, REG_A), (B, REG_B), (X, REG_X), (S, REG_STATUS)] {
+            self.mem(&[0x88], reg, Mem::Disp(RDI, offset))
+        }
+        self.byte(0x66);
+        match pc {
+            Pc::Imm(v) => {
+                self.mem(&[0xc7], 0, Mem::Disp(RDI, REG_PC));
+                self.bytes(&v.to_le_bytes())
+            }
+            Pc::Ax => self.mem(&[0x89], RAX, Mem::Disp(RDI, REG_PC))
+        }
+        self.mov_eax(n as u32);
+        self.bytes(&[0x5b, 0xc3]) // pop rbx, ret
+    }
+    /// the effective address of a wide instruction, into eax
+    fn effective_address(&mut self, d: &Decoded) {
+        if d.op & 0b0001_0000 != 0 { // indirect, big endian
+            self.mem(&[0x0f, 0xb6], RCX, Mem::Disp(RSI, d.operand as i32));
+            self.mem(&[0x0f, 0xb6], RAX, Mem::Disp(RSI, d.operand.wrapping_add(1) as i32));
+            self.bytes(&[0xc1, 0xe1, 8]); // shl ecx, 8
+            self.bytes(&[0x09, 0xc8]) // or eax, ecx
+        }
+        else {
+            self.mov_eax(d.operand as u32)
+        }
+        if d.op & 0b1000 != 0 { // offset by x, wrapping
+            self.rex(RCX, 0, X);
+            self.bytes(&[0x0f, 0xb6, 0xc0 | (RCX & 7) << 3 | X & 7]); // movzx ecx, r10b
+            self.bytes(&[0x01, 0xc8]); // add eax, ecx
+            self.bytes(&[0x0f, 0xb7, 0xc0]) // movzx eax, ax
+        }
+    }
+
+    /// `next` is the address of the instruction after, and `n` how many have run once this one has
+    fn instruction(&mut self, d: &Decoded, isa: Isa, next: u16, n: usize, flags_live: bool) {
+        let zn = |asm: &mut Asm| {
+            if let (true, Some(v)) = (flags_live, zn_source(d.op, isa)) {
+                asm.zero_negative(v, isa)
+            }
+        };
+        if d.op & 0b1000_0000 == 0 {
+            self.single_width(d, isa);
+            zn(self);
+            return
+        }
+        self.effective_address(d);
+        let branch = if d.op & 0b0010_0000 != 0 {
+            let (flag, cc) = match d.op & 0b111 {
+                0b000 => (ZERO, CC_Z), // jnz
+                0b001 => (CARRY, CC_NZ), // jcs
+                0b010 => (CARRY, CC_Z), // jcc
+                0b011 => (NEGATIVE, CC_NZ), // jmi
+                0b100 => (NEGATIVE, CC_Z), // jpl
+                0b101 => (OVERFLOW, CC_NZ), // jvs
+                _ => (OVERFLOW, CC_Z) // jvc
+            };
+            self.test_imm(S, flag);
+            self.setcc(cc, RCX);
+            true
+        }
+        else {
+            match d.op & 0b111 {
+                0b000 => { // lda
+                    self.mem(&[0x8a], A, Mem::Index(RSI, RAX));
+                    zn(self);
+                    return
+                }
+                0b001 => { // sta, leaving early if it wrote over code
+                    self.mem(&[0x88], A, Mem::Index(RSI, RAX));
+                    zn(self);
+                    self.mem(&[0x80], 7, Mem::Index(RDX, RAX)); // cmp byte [rdx + rax], 0
+                    self.byte(0);
+                    let clean = self.jcc(CC_Z);
+                    self.byte(0x66);
+                    self.mem(&[0x89], RAX, Mem::Disp(RDI, REG_WRITTEN));
+                    self.mem(&[0xc6], 0, Mem::Disp(RDI, REG_WROTE));
+                    self.byte(1);
+                    self.exit(Pc::Imm(next), n);
+                    self.patch(clean);
+                    return
+                }
+                0b010 => false, // jmp
+                0b100 => { // jez
+                    self.test_imm(S, ZERO);
+                    self.setcc(CC_NZ, RCX);
+                    true
+                }
+                _ => { // jgt
+                    self.rr(0x38, B, A); // cmp r8b, r9b
+                    self.setcc(CC_A, RCX);
+                    true
+                }
+            }
+        };
+        zn(self);
+        if branch {
+            self.rr(0x84, RCX, RCX);
+            let not_taken = self.jcc(CC_Z);
+            self.exit(Pc::Ax, n);
+            self.patch(not_taken);
+            self.exit(Pc::Imm(next), n)
+        }
+        else {
+            self.exit(Pc::Ax, n)
+        }
+    }
+
+    fn single_width(&mut self, d: &Decoded, isa: Isa) {
+        match d.op {
+            0 => {} // nop
+            2 => self.rr(0x86, B, A), // swp: xchg
+            3 => self.rr(0x88, A, B), // tab
+            4 => self.rr(0x88, A, X), // tax
+            5 => self.rr(0x88, X, A), // txa
+            6 => self.ext(&[0xfe], 0, X), // inc
+            7 => self.ext(&[0xfe], 1, X), // dec
+            8 => { // add
+                self.carry_in();
+                self.rr(0x10, B, A); // adc
+                self.setcc(0x2, RCX); // setc
+                let v2 = isa == Isa::V2; // v1 has no overflow flag
+                if v2 {
+                    self.setcc(CC_O, RAX)
+                }
+                self.carry_out(v2)
+            }
+            10|11 => { // sub, sbc. the avc carry is the opposite of x86's borrow
+                if d.op == 11 {
+                    self.carry_in();
+                    self.byte(0xf5); // cmc
+                    self.rr(0x18, B, A) // sbb
+                }
+                else {
+                    self.rr(0x28, B, A) // sub
+                }
+                self.setcc(CC_NC, RCX);
+                self.setcc(CC_O, RAX);
+                self.carry_out(true)
+            }
+            12|13 => { // lsr and lsl rotate through the carry
+                self.carry_in();
+                self.ext(&[0xd0], if d.op == 12 { 2 } else { 3 }, A); // rcl, rcr
+                self.setcc(0x2, RCX);
+                self.carry_out(false)
+            }
+            14 => self.ri(4, S, !CARRY), // clc
+            15 => self.ri(1, S, CARRY), // sec
+            19 => self.rr(0x88, S, A), // gst
+            21 => { // ssp
+                self.rex(RAX, 0, A);
+                self.bytes(&[0x0f, 0xb6, 0xc0]); // movzx eax, r8b
+                self.bytes(&[0xc1, 0xe0, 8]); // shl eax, 8
+                self.rr(0x08, B, RAX);
+                self.byte(0x66);
+                self.mem(&[0x89], RAX, Mem::Disp(RDI, REG_SP))
+            }
+            22 => { // gsp
+                self.mem(&[0x0f, 0xb7], RAX, Mem::Disp(RDI, REG_SP));
+                self.rr(0x88, RAX, B);
+                self.bytes(&[0xc1, 0xe8, 8]); // shr eax, 8
+                self.rr(0x88, RAX, A)
+            }
+            25 => { // lda const
+                self.rex(0, 0, A);
+                self.bytes(&[0xb0 | A & 7, d.operand as u8])
+            }
+            27 => self.ext(&[0xf6], 2, A), // not
+            28 => self.rr(0x20, B, A), // and
+            29 => self.rr(0x08, B, A), // ior
+            30 => self.rr(0x30, B, A), // xor
+            33 => self.ri(4, S, !INTERRUPT_ENABLE), // cli
+            35 => { // cmp, into al
+                self.rr(0x88, A, RAX);
+                self.rr(0x28, B, RAX);
+                self.setcc(CC_NC, RCX);
+                self.carry_out(false)
+            }
+            _ => unreachable!()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::assembler::assemble_with_isa;
+    use crate::decode::decode;
+    use crate::processor::{Processor, ProcessorBuilder};
+
+    #[test]
+    fn block() {
+        let (prog, _) = assemble_with_isa("
+        lda #0x7f
+        swp
+        lda #1
+        sec
+        add
+        sta 0x1000
+        tax
+        dec
+        jvs 0x1234
+        hlt", Isa::V2).unwrap();
+        let mut memory = [0; 65536];
+        memory[..prog.len()].copy_from_slice(&prog);
+        let mut instrs = Vec::new();
+        let mut pc = 0;
+        for _ in 0..9 {
+            let d = decode(&memory, pc, Isa::V2);
+            pc += d.len as u16;
+            instrs.push(d)
+        }
+        let native = Native::compile(&instrs, 0, Isa::V2).unwrap();
+        assert_eq!(native.instrs, 9);
+        let mut regs = Regs::default();
+        let mut covered = vec![0; 65536];
+        assert_eq!(native.run(&mut regs, &mut memory, &covered), 9);
+        assert_eq!((regs.a, regs.b, regs.x, regs.pc), (0x81, 0x7f, 0x80, 0x1234));
+        assert_eq!(regs.status, NEGATIVE | OVERFLOW);
+        assert_eq!(memory[0x1000], 0x81);
+
+        // stores over code stop straight after
+        covered[0x1000] = 1;
+        let mut regs = Regs::default();
+        assert_eq!(native.run(&mut regs, &mut memory, &covered), 6);
+        assert_eq!((regs.pc, regs.wrote, regs.written), (10, 1, 0x1000));
+
+        assert!(Native::compile(&[decode(&memory, 15, Isa::V2)], 15, Isa::V2).is_none()) // hlt
+    }
+
+    /// add, sub, sbc, lsr, lsl and cmp for every a, b and carry, natively and interpreted, with the flags
+    /// they don't set left in different states
+    #[test]
+    fn alu_flags() {
+        for isa in [Isa::V1, Isa::V2] {
+            for op in [8, 10, 11, 12, 13, 35] {
+                if !isa.has_opcode(op) {
+                    continue
+                }
+                let mut memory = [0; 65536];
+                memory[0] = op;
+                let native = Native::compile(&[decode(&memory, 0, isa)], 0, isa).unwrap();
+                let covered = vec![0; 65536];
+                let mut p = ProcessorBuilder::new().memory(&[op]).isa(isa).output(io::sink()).input(io::empty()).build();
+                for a in 0..=255 {
+                    for b in 0..=255 {
+                        for carry in [0, CARRY] {
+                            let status = carry | if a & 1 != 0 { OVERFLOW } else { 0 } | if b & 1 != 0 { NEGATIVE } else { 0 }
+                                | if (a ^ b) & 2 != 0 { ZERO } else { 0 };
+                            let mut regs = Regs { a, b, status, ..Regs::default() };
+                            assert_eq!(native.run(&mut regs, &mut memory, &covered), 1);
+                            (p.a, p.b, p.status, p.program_counter) = (a, b, status, 0);
+                            p.step().unwrap();
+                            assert_eq!((regs.a, regs.b, regs.status, regs.pc), (p.a, p.b, p.status, p.program_counter),
+                                "{:?} op {} a {} b {} status {:#b}", isa, op, a, b, status);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn patches_itself() {
+        // once the loop's hot, it starts writing the count into the `lda #` after the store, in the same block
+        let (prog, _) = assemble_with_isa("
+        lda #40
+        sta COUNT
+        jmp LOOP
+        org 0x0100
+LOOP:   lda COUNT
+        tax
+        dec
+        txa
+        sta COUNT
+        sta (PTR)
+PATCH:  lda #0
+        sta 0x1000,x
+        txa
+        swp
+        lda #20
+        cmp
+        jnz NEXT
+        lda #0x01
+        sta PTR
+        lda #0x0d
+        sta PTR+1
+NEXT:   txa
+        jnz LOOP
+        hlt
+PTR:    dat 0x20
+        dat 0x00
+COUNT:  dat 0", Isa::V2).unwrap();
+        let run = |native: bool| {
+            let mut p = ProcessorBuilder::new().memory(&prog).isa(Isa::V2).output(io::sink()).input(io::empty()).build();
+            p.jit = native;
+            while !p.halted {
+                let _ = if native { p.run_fast() } else { p.step() };
+            }
+            p.snapshot()
+        };
+        let s = run(true);
+        assert_eq!(&s.memory[0x1011..0x1015], &[17, 18, 19, 0]);
+        assert_eq!(s, run(false))
+    }
+
+    /// random programs of mostly translatable instructions, with branches anywhere and stores over code,
+    /// have to run the same natively and one step at a time
+    #[test]
+    fn same_as_stepping() {
+        let mut seed = 0x2545_f491u32;
+        let mut rng = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        for isa in [Isa::V1, Isa::V2] {
+            for _ in 0..200 {
+                let mut prog = Vec::new();
+                let mut starts = Vec::new();
+                while prog.len() < 48 {
+                    starts.push(prog.len() as u16);
+                    let op = match rng() % 16 {
+                        0 => [1, 16, 17, 18, 24][rng() as usize % 5], // hlt, put, psa, ppa, rts aren't translated
+                        1..=5 => (rng() as u8) | 0b1000_0000,
+                        _ => rng() as u8 % 36
+                    };
+                    if !isa.has_opcode(op) || op == 23 {
+                        continue
+                    }
+                    prog.push(op);
+                    if op == 25 {
+                        prog.push(rng() as u8)
+                    }
+                    else if op & 0b1000_0000 != 0 {
+                        let target = match op & 0b0010_0111 { // loads and stores near the code, jumps into it
+                            0b000|0b001 => rng() as u16 % 96,
+                            _ => starts[rng() as usize % starts.len()]
+                        };
+                        prog.extend_from_slice(&[(target >> 8) as u8, target as u8])
+                    }
+                }
+                let run = |native: bool| {
+                    let mut p: Processor = ProcessorBuilder::new().memory(&prog).isa(isa).output(io::sink()).input(io::empty()).build();
+                    p.jit = native;
+                    p.stack_pointer = 0x4000;
+                    p.cycle_budget = Some(5000);
+                    while !p.halted {
+                        let _ = if native { p.run_fast() } else { p.step() };
+                    }
+                    p.snapshot()
+                };
+                assert_eq!(run(true), run(false), "{:?} {:?}", isa, prog)
+            }
+        }
+    }
+}
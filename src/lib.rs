@@ -0,0 +1,22 @@
+pub mod processor;
+pub mod bus;
+pub mod utils;
+pub mod assembler;
+pub mod condition;
+pub mod coverage;
+pub mod decode;
+pub mod debugger;
+pub mod disassembler;
+pub mod executable;
+pub mod gdb;
+pub mod hexdump;
+pub mod profiler;
+pub mod history;
+pub mod isa;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod replay;
+pub mod tester;
+pub mod snapshot;
+pub mod state;
+pub mod trace;
@@ -1,12 +1,16 @@
-use argparse::{ArgumentParser, Store, StoreConst};
+use argparse::{ArgumentParser, Store, StoreConst, StoreTrue};
 use std::process::exit;
 
-use processor::Processor;
-use utils::{Options, Command};
-
-mod processor;
-mod utils;
-mod assembler;
+use avc_asm::bus::Bus;
+use avc_asm::processor::{Processor, Fault, FaultPolicy};
+use avc_asm::utils::{Options, Command};
+use avc_asm::coverage::{self, Coverage};
+use avc_asm::isa::Isa;
+use avc_asm::replay::{self, InputLog, InputRecorder};
+use avc_asm::snapshot::Snapshot;
+use avc_asm::state::StateFormat;
+use avc_asm::trace::{Tracer, TraceFormat};
+use avc_asm::{assembler, debugger, gdb, profiler, tester, trace};
 
 fn main() {
     match run_program() {
@@ -20,6 +24,10 @@ fn main() {
 
 fn run_program() -> Result<(), (i32, String)> {
     let po = get_options();
+    let bus = match Bus::from_options(&po) {
+        Ok(b) => b,
+        Err(e) => return Err((1, e))
+    };
 
     match po.command {
         Command::Assemble => {
@@ -29,25 +37,130 @@ fn run_program() -> Result<(), (i32, String)> {
             }
         }
         Command::Run => {
-            let mut p = Processor::new(&po);
-            p.run(&po)
+            if !po.coverage.is_empty() {
+                return Err((1, String::from("coverage needs the source file, so use -R or -t")))
+            }
+            // a saved state has all of memory, so the binary is optional
+            let p = if po.path.is_empty() && !po.load_state.is_empty() {
+                Processor::new_with_memory(&[])
+            }
+            else {
+                match Processor::new(&po) {
+                    Ok(p) => p,
+                    Err(e) => return Err((1, e))
+                }
+            };
+            if let Err(e) = run_processor(p, bus, &po) {
+                return Err((1, e))
+            }
         }
         Command::AssAndRun => {
-            let prog = match assembler::assemble(&po) {
+            let (prog, debug) = match assembler::assemble(&po) {
                 Ok(p) => p,
                 Err(e) => return Err((1, e))
             };
             let mut p = Processor::new_with_memory(&prog);
-            p.run(&po)
+            p.isa = match Isa::from_options(&po) {
+                Ok(isa) => isa.unwrap_or_default(),
+                Err(e) => return Err((1, e))
+            };
+            p.start_at_entry(&debug);
+            p.symbols = Some(debug.labels);
+            if let Err(e) = run_processor(p, bus, &po) {
+                return Err((1, e))
+            }
+        }
+        Command::Debug => {
+            if let Err(e) = debugger::run_debugger(&po) {
+                return Err((1, e))
+            }
+        }
+        Command::Profile => {
+            if let Err(e) = profiler::run_profile(&po) {
+                return Err((1, e))
+            }
+        }
+        Command::Test => {
+            if let Err(e) = tester::run_tests(&po) {
+                return Err((1, e))
+            }
         }
     }
 
     Ok(())
 }
 
+fn run_processor(mut p: Processor, bus: Bus, po: &Options) -> Result<(), String> {
+    if !po.record_input.is_empty() && !po.replay_input.is_empty() {
+        return Err(String::from("input can't be recorded and replayed at the same time"))
+    }
+    if !po.dump_mem_file.is_empty() && po.dump_mem.is_empty() {
+        return Err(String::from("--dump-mem-file needs --dump-mem to say what to write"))
+    }
+    let dump_format = if po.dump_state.is_empty() { None } else { Some(StateFormat::from_name(&po.dump_state)?) };
+    p.bus = bus;
+    p.fault_policy = po.fault_policy;
+    if !po.load_state.is_empty() {
+        p.restore(&Snapshot::load(&po.load_state)?)?
+    }
+    // the budget is for this run, not counting cycles from before the state was saved
+    p.cycle_budget = if po.max_cycles == 0 { None } else { Some(p.cycles + po.max_cycles) };
+    if !po.trace.is_empty() {
+        let format = TraceFormat::from_name(&po.trace_format)?;
+        let ranges = trace::parse_ranges(&po.trace_range)?;
+        p.tracer = Some(Tracer::create(&po.trace, format, ranges)?)
+    }
+    if !po.coverage.is_empty() {
+        p.coverage = Some(Coverage::new())
+    }
+    if !po.record_input.is_empty() {
+        p.input_log = Some(InputLog::Record(InputRecorder::create(&po.record_input)?))
+    }
+    if !po.replay_input.is_empty() {
+        p.input_log = Some(InputLog::Replay(replay::load_recording(&po.replay_input)?))
+    }
+    let result = if po.gdb.is_empty() {
+        p.run(po)
+    }
+    else {
+        gdb::serve(&mut p, &po.gdb)
+    };
+    if let Some(t) = p.tracer.take() {
+        if let Err(e) = t.finish() {
+            return Err(format!("unable to write trace file {}: {}", po.trace, e))
+        }
+    }
+    if let Some(InputLog::Record(r)) = p.input_log.take() {
+        if let Err(e) = r.finish() {
+            return Err(format!("unable to write input recording {}: {}", po.record_input, e))
+        }
+    }
+    if let Some(c) = &p.coverage {
+        coverage::write_lcov(c, po)?
+    }
+    if !po.save_state.is_empty() {
+        let mut s = p.snapshot();
+        // running out of cycles is a good place to stop and carry on later, so it's saved as not halted
+        if s.fault == Some(Fault::CycleBudgetExceeded) {
+            s.halted = false;
+            s.fault = None
+        }
+        s.save(&po.save_state)?
+    }
+    match dump_format {
+        Some(StateFormat::Text) => println!("{}", p.state()),
+        Some(StateFormat::Json) => println!("{}", p.state().to_json()),
+        None => {}
+    }
+    result
+}
+
+#[allow(clippy::field_reassign_with_default)]
 fn get_options() -> Options {
     let mut o = Options::default();
     o.out_path = String::from("a.out");
+    let mut fault_policy = String::from("halt");
+    o.trace_format = String::from("json");
 
     {
         let mut ap = ArgumentParser::new();
@@ -56,12 +169,36 @@ fn get_options() -> Options {
             .add_option(&["-a"], StoreConst(Command::Assemble), "assemble")
             .add_option(&["-r"], StoreConst(Command::Run), "run")
             .add_option(&["-R"], StoreConst(Command::AssAndRun), "assemble and run")
+            .add_option(&["-t"], StoreConst(Command::Test), "assemble and run the tests in a source file")
+            .add_option(&["-D", "--debug"], StoreConst(Command::Debug), "assemble and debug interactively")
+            .add_option(&["-P", "--profile"], StoreConst(Command::Profile), "assemble, run and print a profile")
             .required()
         ;
         ap.refer(&mut o.path).add_argument("file", Store, "the file to run/assemble");
         ap.refer(&mut o.out_path).add_option(&["-o"], Store, "Output file path (for assembly)");
+        ap.refer(&mut o.executable).add_option(&["--exe"], StoreTrue, "With -a, write an executable, with an entry point, stack pointer and symbols, instead of a raw image");
+        ap.refer(&mut o.strip).add_option(&["--strip"], StoreTrue, "Leave the symbol table out of an executable");
+        ap.refer(&mut o.isa).add_option(&["--isa"], Store, "Instruction set revision: v1 or v2. Default is v1, or whatever a binary's header says");
         ap.refer(&mut o.debug_level).add_option(&["-d"], Store, "Debug level. 0 is none, 1 is readout on break, 2 is 1+instructions, 3 is readout every cycle");
+        ap.refer(&mut o.memory_map).add_option(&["-m"], Store, "Memory map file, for attaching devices");
+        ap.refer(&mut o.timer).add_option(&["--timer"], StoreTrue, "Attach the programmable timer at 0xfff0");
         ap.refer(&mut o.clock_period).add_option(&["-p"], Store, "The time in miliseconds to wait every clock cycle. Default is 0.");
+        ap.refer(&mut o.clock_speed).add_option(&["-f"], Store, "Target clock frequency in Hz. Default is 0, which runs as fast as possible.");
+        ap.refer(&mut fault_policy).add_option(&["--faults"], Store, "What to do on a fault: halt, trap or ignore. Default is halt.");
+        ap.refer(&mut o.gdb).add_option(&["--gdb"], Store, "Serve the processor to gdb on host:port, or a unix socket path, instead of running it");
+        ap.refer(&mut o.trace).add_option(&["--trace"], Store, "Write a record of every instruction executed to this file");
+        ap.refer(&mut o.trace_format).add_option(&["--trace-format"], Store, "Trace format: json (lines) or binary. Default is json.");
+        ap.refer(&mut o.trace_range).add_option(&["--trace-range"], Store, "Only trace instructions in these address ranges, like 0x10:0x20,0x300:0x3ff");
+        ap.refer(&mut o.folded).add_option(&["--folded"], Store, "With -P, also write folded call stacks to this file, for flame graphs");
+        ap.refer(&mut o.coverage).add_option(&["--coverage"], Store, "With -R or -t, write lcov coverage of the source file to this file");
+        ap.refer(&mut o.save_state).add_option(&["--save-state"], Store, "Save the machine to this file when the run ends");
+        ap.refer(&mut o.load_state).add_option(&["--load-state"], Store, "Start from a machine saved with --save-state. With -r, the file to run is optional");
+        ap.refer(&mut o.record_input).add_option(&["--record-input"], Store, "Record the input the program reads, and the cycle it arrives at, to this file");
+        ap.refer(&mut o.replay_input).add_option(&["--replay-input"], Store, "Give the program the input recorded in this file instead of reading it");
+        ap.refer(&mut o.dump_mem).add_option(&["--dump-mem"], Store, "Hexdump these address ranges, like 0x300:0x3ff, on brk and when the run ends");
+        ap.refer(&mut o.dump_mem_file).add_option(&["--dump-mem-file"], Store, "Write the raw bytes of the --dump-mem ranges to this file when the run ends");
+        ap.refer(&mut o.dump_state).add_option(&["--dump-state"], Store, "Print the registers and flags when the run ends, as text or json");
+        ap.refer(&mut o.max_cycles).add_option(&["--max-cycles"], Store, "Fault after this many cycles. Default is 0, which is unlimited.");
 
         ap.parse_args_or_exit()
     }
@@ -69,6 +206,13 @@ fn get_options() -> Options {
         println!("invalid debug level {}", o.debug_level);
         exit(2)
     }
+    o.fault_policy = match FaultPolicy::from_name(&fault_policy) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("{}", e);
+            exit(2)
+        }
+    };
 
     o
 }
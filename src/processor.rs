@@ -1,11 +1,28 @@
-use std::io::{Write, stdout, Read};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{Write, stdout, Read, ErrorKind};
 use std::fs::read;
 //use std::num::Wrapping;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use termion::async_stdin;
 
+use crate::bus::Bus;
+use crate::condition::Condition;
+use crate::coverage::Coverage;
+#[cfg(feature = "jit")]
+use crate::decode::Block;
+use crate::decode::{decode, BlockCache, Decoded};
+use crate::disassembler::disassemble;
+use crate::executable::{self, Executable};
+use crate::hexdump;
+use crate::history::History;
+use crate::isa::{self, Isa};
+#[cfg(feature = "jit")]
+use crate::jit::Regs;
+use crate::replay::{Chunk, InputLog};
+use crate::trace::{self, Tracer};
 use crate::utils::{bytes_to_16, u16_to_bytes, Options};
 
 pub struct Processor {
@@ -13,24 +30,255 @@ pub struct Processor {
     pub a: u8,
     pub b: u8,
     pub x: u8,
-    pub program_counter: usize, // so rust stops complaining
+    pub program_counter: u16, // all address arithmetic wraps at 16 bits
     pub status: u8,
     pub halted: bool,
-    pub stack_pointer: usize, // see note on pc
+    pub stack_pointer: u16,
     pub write_buffer: Box<dyn Write>,
     pub reader: Box<dyn Read>,
-    pub get_buffer: Vec<u8>
+    pub get_buffer: Vec<u8>,
+    pub bus: Bus,
+    pub cycles: u64,
+    pub fault_policy: FaultPolicy,
+    pub cycle_budget: Option<u64>,
+    pub fault: Option<Fault>, // the fault that halted the processor
+    pub fault_pc: u16, // where the last fault happened
+    // the address and opcode of the instruction the last `step` executed, or None if it didn't execute one. after
+    // an interrupt, that's the handler's first instruction rather than the one the pc was on
+    pub last_instr: Option<(u16, u8)>,
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub history: Option<History>, // see record_history
+    pub tracer: Option<Tracer>,
+    pub coverage: Option<Coverage>,
+    pub isa: Isa,
+    pub input_log: Option<InputLog>, // recording or replaying input
+    pub(crate) rewound_input: VecDeque<Chunk>, // input that step_back put back, to be read again before anything new
+    pub symbols: Option<HashMap<String, usize>>, // labels to name addresses with when printing instructions
+    #[cfg(feature = "jit")]
+    pub jit: bool, // whether run_fast translates hot blocks to native code. on by default
+    pending_fault: Option<Fault>,
+    watch_hit: Option<WatchHit>,
+    throttle: Option<Throttle>,
+    block_cache: BlockCache
 }
 
-/// status flags:
-/// 0: carry
-/// 1: zero
-/// 2: negative
-/// 3: 
+/// things a program can do wrong that would otherwise crash the emulator or be silently ignored
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    StackOverflow,
+    StackUnderflow,
+    IllegalOpcode(u8),
+    Io(String), // the error's message, since io::Error can't be cloned
+    CycleBudgetExceeded,
+    Unmapped(u16)
+}
+impl Fault {
+    /// what a trap handler finds in `a`
+    pub fn code(&self) -> u8 {
+        match self {
+            Fault::StackOverflow => 1,
+            Fault::StackUnderflow => 2,
+            Fault::IllegalOpcode(_) => 3,
+            Fault::Io(_) => 4,
+            Fault::CycleBudgetExceeded => 5,
+            Fault::Unmapped(_) => 6
+        }
+    }
+}
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::StackOverflow => write!(f, "stack overflow"),
+            Fault::StackUnderflow => write!(f, "stack underflow"),
+            Fault::IllegalOpcode(op) => write!(f, "illegal opcode 0x{:0>2x}", op),
+            Fault::Io(e) => write!(f, "i/o error: {}", e),
+            Fault::CycleBudgetExceeded => write!(f, "cycle budget exceeded"),
+            Fault::Unmapped(addr) => write!(f, "access to unmapped address 0x{:0>4x}", addr)
+        }
+    }
+}
+
+/// what happens when a fault is raised
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum FaultPolicy {
+    /// stop the processor and report the fault
+    #[default]
+    Halt,
+    /// jump through the fault vector like an interrupt, with the fault's code in `a`
+    Trap,
+    /// carry on. stack pointers wrap, illegal opcodes do nothing, unmapped reads give 0
+    Ignore
+}
+impl FaultPolicy {
+    pub fn from_name(s: &str) -> Result<FaultPolicy, String> {
+        match s {
+            "halt" => Ok(FaultPolicy::Halt),
+            "trap" => Ok(FaultPolicy::Trap),
+            "ignore" => Ok(FaultPolicy::Ignore),
+            _ => Err(format!("unknown fault policy {}", s))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepOutcome {
+    Continue,
+    Break, // hit a brk
+    Halted,
+    Trapped(Fault), // a fault was raised and the processor jumped to the fault vector
+    Breakpoint(usize), // the pc reached the breakpoint with this index, and it's about to execute
+    Watchpoint(WatchHit)
+}
+
+/// stops the processor before it executes the instruction at `address`, if `condition` holds.
+/// conditions can use `hits`, the number of times the pc has reached the address including this one
+#[derive(Clone)]
+pub struct Breakpoint {
+    pub address: u16,
+    pub condition: Option<Condition>,
+    pub hits: u64
+}
+impl Breakpoint {
+    pub fn new(address: u16, condition: Option<Condition>) -> Breakpoint {
+        Breakpoint { address, condition, hits: 0 }
+    }
+}
+
+/// stops the processor after an instruction reads or writes memory from `start` to `end` inclusive.
+/// `lda`, `sta` and the stack all count, but fetching instructions and indirect addresses doesn't
+#[derive(Clone)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+    pub hits: u64
+}
+impl Watchpoint {
+    pub fn new(start: u16, end: u16, read: bool, write: bool) -> Watchpoint {
+        Watchpoint { start, end, read, write, hits: 0 }
+    }
+}
+
+/// the access that set off a watchpoint
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchHit {
+    pub index: usize,
+    pub pc: u16, // the instruction that made the access
+    pub address: u16,
+    pub value: u8,
+    pub write: bool
+}
+
+struct Throttle {
+    start: Instant,
+    start_cycles: u64,
+    next_check: u64
+}
+const THROTTLE_CHECKS_PER_SECOND: u64 = 100;
+
+/// taking an interrupt costs as much as a jsr and a psa
+const INTERRUPT_CYCLES: u64 = 6;
+
+/// how many cycles an instruction takes. every byte fetched and every memory or stack access
+/// costs 1, and so do i/o port accesses, adding an offset and restoring a return address
+pub fn cycle_cost(instr: u8) -> u64 {
+    if instr & 0b1000_0000 == 0 {
+        return match instr {
+            25 => 2, // lda const, fetches its operand
+            17|18 => 2, // psa, ppa
+            24|34 => 4, // rts, rti: pop 2 bytes and restore the pc
+            16|26|31 => 2, // put, get, gbf: one port access
+            _ => 1
+        }
+    }
+    let mut cost = 3; // instruction and address
+    if instr & 0b0001_0000 != 0 { // indirect reads 2 more bytes
+        cost += 2
+    }
+    if instr & 0b1000 != 0 { // offset, adds x to the address
+        cost += 1
+    }
+    cost + match instr & 0b0010_0111 { // flag branches have bit 5 set
+        0b000|0b001 => 1, // lda, sta
+        0b011 => 2, // jsr pushes the return address
+        _ => 0
+    }
+}
+
+/// `jez`, `jgt` and the flag branches
+pub fn is_conditional_branch(instr: u8) -> bool {
+    matches!(instr & 0b1110_0111, 0b1000_0100|0b1000_0101|0b1010_0000..=0b1010_0110)
+}
+
+// status flags. see avc.md for which instructions set which
+pub const CARRY: u8 = 0b1;
+pub const ZERO: u8 = 0b10;
+pub const INTERRUPT_ENABLE: u8 = 0b100;
+pub const OVERFLOW: u8 = 0b0100_0000;
+pub const NEGATIVE: u8 = 0b1000_0000;
+/// the address of the irq handler is stored here, big endian
+pub const IRQ_VECTOR: usize = 0xfffe;
+/// the address of the fault handler, for the trap policy
+pub const FAULT_VECTOR: usize = 0xfffc;
 
 impl Default for Processor {
     fn default() -> Processor {
-        Processor {
+        ProcessorBuilder::new().build()
+    }
+}
+
+/// sets up a processor with something other than the terminal for input and output.
+///
+/// input is polled whenever the program runs `get` or `gbf`, and before every instruction while interrupts are
+/// enabled. a read of 0 bytes or a `WouldBlock` error
+/// means there's no input right now, which is how both the end of a file and a quiet terminal look to
+/// the program. any other error, from either side, raises `Fault::Io`.
+pub struct ProcessorBuilder {
+    memory: Vec<u8>,
+    output: Option<Box<dyn Write>>,
+    input: Option<Box<dyn Read>>,
+    bus: Bus,
+    pub(crate) isa: Isa
+}
+impl ProcessorBuilder {
+    pub fn new() -> ProcessorBuilder {
+        ProcessorBuilder {
+            memory: Vec::new(),
+            output: None,
+            input: None,
+            bus: Bus::default(),
+            isa: Isa::default()
+        }
+    }
+    /// copied into memory starting at address 0
+    pub fn memory(mut self, mem: &[u8]) -> ProcessorBuilder {
+        self.memory = mem.to_vec();
+        self
+    }
+    /// where `put` sends bytes. defaults to stdout
+    pub fn output(mut self, w: impl Write + 'static) -> ProcessorBuilder {
+        self.output = Some(Box::new(w));
+        self
+    }
+    /// where `get` and `gbf` read from. defaults to the terminal, without blocking
+    pub fn input(mut self, r: impl Read + 'static) -> ProcessorBuilder {
+        self.input = Some(Box::new(r));
+        self
+    }
+    /// the instruction set to decode. defaults to v1
+    pub fn isa(mut self, isa: Isa) -> ProcessorBuilder {
+        self.isa = isa;
+        self
+    }
+    /// devices to map into the address space
+    pub fn bus(mut self, bus: Bus) -> ProcessorBuilder {
+        self.bus = bus;
+        self
+    }
+    pub fn build(self) -> Processor {
+        let mut p = Processor {
             memory: [0;65536],
             a: 0,
             b: 0,
@@ -39,112 +287,481 @@ impl Default for Processor {
             status: 0,
             halted: false,
             stack_pointer: 0,
-            write_buffer: Box::new(stdout()),
-            reader: Box::new(async_stdin()),
-            get_buffer: Vec::new()
+            write_buffer: match self.output {
+                Some(w) => w, None => Box::new(stdout())
+            },
+            reader: match self.input {
+                Some(r) => r, None => Box::new(async_stdin())
+            },
+            get_buffer: Vec::new(),
+            bus: self.bus,
+            cycles: 0,
+            fault_policy: FaultPolicy::Halt,
+            cycle_budget: None,
+            fault: None,
+            fault_pc: 0,
+            last_instr: None,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            history: None,
+            tracer: None,
+            coverage: None,
+            isa: self.isa,
+            input_log: None,
+            rewound_input: VecDeque::new(),
+            symbols: None,
+            #[cfg(feature = "jit")]
+            jit: true,
+            pending_fault: None,
+            watch_hit: None,
+            throttle: None,
+            block_cache: BlockCache::default()
+        };
+        for (i,v) in self.memory.iter().enumerate() {
+            p.memory[i] = *v
         }
+        p
+    }
+}
+impl Default for ProcessorBuilder {
+    fn default() -> ProcessorBuilder {
+        Self::new()
     }
 }
 
 impl Processor {
     pub fn new_with_memory(mem: &[u8]) -> Processor {
-        let mut p = Self::default();
-        for (i,v) in mem.iter().enumerate() {
-            p.memory[i] = *v
-        }
-        p
+        ProcessorBuilder::new().memory(mem).build()
     }
-    pub fn new(po: &Options) -> Processor {
-        let mem = read(&po.path).unwrap();
-        Self::new_with_memory(&mem)
+    /// loads a binary, which is either an executable or a raw image that may start with a header saying
+    /// which isa it's for
+    pub fn new(po: &Options) -> Result<Processor, String> {
+        let bytes = match read(&po.path) {
+            Ok(b) => b,
+            Err(_) => return Err(format!("unable to read file {}", po.path))
+        };
+        if executable::is_executable(&bytes) { // first, since executables start with the header magic too
+            let exe = Executable::from_bytes(&bytes).map_err(|e| format!("{}: {}", po.path, e))?;
+            isa::choose(Some(exe.isa), Isa::from_options(po)?)?;
+            let mut p = Processor::default();
+            p.load_executable(&exe);
+            return Ok(p)
+        }
+        let (header, mem) = isa::read_header(&bytes)?;
+        let isa = isa::choose(header, Isa::from_options(po)?)?;
+        Ok(ProcessorBuilder::new().memory(mem).isa(isa).build())
     }
-    #[allow(dead_code)]
+    /// `state()`, formatted for people. use `state()` to look at the registers from code
     pub fn readout(&self) -> String {
-        let mut ret = String::new();
-        ret.push_str(&format!("a :   0x{:0>2x}\n", self.a));
-        ret.push_str(&format!("b :   0x{:0>2x}\n", self.b));
-        ret.push_str(&format!("x :   0x{:0>2x}\n", self.x));
-        ret.push_str(&format!("pc: 0x{:0>4x}\n", self.program_counter));
-        ret.push_str(&format!("sp: 0x{:0>4x}\n", self.stack_pointer));
-        ret.push_str(&format!("      ------zc\n"));
-        ret.push_str(&format!("s : 0b{:0>8b}\n", self.status));
-
-        ret
+        format!("{}\n", self.state())
     }
 
-    pub fn run(&mut self, po: &Options) {
+    /// returns an error if the processor was halted by a fault. `--dump-mem` is printed on every `brk`
+    /// and when it halts
+    pub fn run(&mut self, po: &Options) -> Result<(), String> {
+        self.run_dumping(po, &mut stdout())
+    }
+    /// `run`, with the `--dump-mem` hexdumps written to `out` instead of stdout
+    pub(crate) fn run_dumping(&mut self, po: &Options, out: &mut dyn Write) -> Result<(), String> {
+        let dump = trace::parse_ranges(&po.dump_mem)?;
         match po.debug_level {
-            0 => self.execute_until_halt(po),
+            0 if dump.is_empty() => self.execute_until_halt(po),
+            0 => {
+                while !self.halted {
+                    if self.execute(false, po) {
+                        self.print_dump(&dump, out)?
+                    }
+                }
+                println!()
+            }
             1|2 => {
                 while !self.halted {
                     self.execute_until_break(po.debug_level == 2, po);
-                    println!("{}", self.readout())
+                    println!("{}", self.readout());
+                    if !self.halted {
+                        self.print_dump(&dump, out)?
+                    }
                 }
             }
             3 => {
                 while !self.halted {
-                    self.execute(true, po);
-                    println!("{}", self.readout())
+                    let brk = self.execute(true, po);
+                    println!("{}", self.readout());
+                    if brk {
+                        self.print_dump(&dump, out)?
+                    }
                 }
             }
             _ => unreachable!()
         }
+        self.print_dump(&dump, out)?;
+        if !po.dump_mem_file.is_empty() {
+            hexdump::write_regions(&self.memory, &dump, &po.dump_mem_file)?
+        }
+        match &self.fault {
+            Some(f) => Err(format!("fault at 0x{:0>4x}: {}", self.fault_pc, f)),
+            None => Ok(())
+        }
     }
 
+    #[allow(clippy::println_empty_string)]
     pub fn execute_until_halt(&mut self, po: &Options) {
         while !self.halted {
-            self.execute(false, po);
+            if po.clock_period == 0 && po.clock_speed == 0 {
+                let _ = self.run_fast();
+            }
+            else {
+                self.execute(false, po);
+            }
         }
         println!("")
     }
+    #[allow(clippy::println_empty_string)]
     pub fn execute_until_break(&mut self, print_instr: bool, po: &Options) {
-        while !self.halted {
-            if self.execute(print_instr, po) {
-                break
+        if !print_instr && po.clock_period == 0 && po.clock_speed == 0 {
+            let _ = self.run_fast();
+        }
+        else {
+            while !self.halted {
+                if self.execute(print_instr, po) {
+                    break
+                }
             }
         }
         println!("")
     }
 
-    fn update_input_buf(&mut self) {
-        self.reader.read_to_end(&mut self.get_buffer).unwrap();
+    fn print_dump(&self, ranges: &[(u16, u16)], out: &mut dyn Write) -> Result<(), String> {
+        for (start, end) in ranges {
+            if let Err(e) = write!(out, "{}", self.hexdump(*start, *end)) {
+                return Err(format!("unable to print memory dump: {}", e))
+            }
+        }
+        Ok(())
     }
 
-    fn execute(&mut self, print_instr: bool, po: &Options) -> bool { // returns true if instr is break
-        if self.program_counter > u16::MAX as usize {
-            self.program_counter %= u16::MAX as usize
+    fn update_input_buf(&mut self) {
+        if self.reread_input() || self.replay_input() {
+            return
         }
-        let instr = self.memory[self.program_counter];
+        let mut chunk = [0; 256];
+        loop { // one read per poll, so a blocking reader only blocks until it has something
+            match self.reader.read(&mut chunk) {
+                Ok(0) => {} // nothing for now
+                Ok(n) => {
+                    self.get_buffer.extend_from_slice(&chunk[..n]);
+                    self.log_input(&chunk[..n]);
+                    if let Some(h) = &mut self.history {
+                        h.record_arrival(self.cycles, &chunk[..n])
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => self.raise(Fault::Io(e.to_string()))
+            }
+            break
+        }
+    }
+    /// faults are raised in the middle of an instruction, and dealt with once it's finished
+    fn raise(&mut self, f: Fault) {
+        if self.pending_fault.is_none() {
+            self.pending_fault = Some(f)
+        }
+    }
+
+    pub fn execute(&mut self, print_instr: bool, po: &Options) -> bool { // returns true if instr is break
         if print_instr {
-            println!("{}", instr)
+            let (instr, _) = disassemble(&self.memory, self.program_counter as usize, self.isa, self.symbols.as_ref());
+            println!("0x{:0>4x}: {}", self.program_counter, instr)
         }
-        if instr == 23 {
-            self.program_counter += 1;
-            return true
+        let outcome = self.step();
+
+        if po.clock_period != 0 {
+            sleep(Duration::from_millis(po.clock_period));
+        }
+        if po.clock_speed != 0 {
+            self.throttle(po.clock_speed)
         }
 
-        match instr & 0b1000_0000 { // msb determines instruction width
-            0 => { // leading zero = single width
-                self.program_counter += 1;
-                self.execute_single_width(instr);
+        matches!(outcome, Ok(StepOutcome::Break|StepOutcome::Breakpoint(_)|StepOutcome::Watchpoint(_)))
+    }
+
+    /// executes one instruction, or takes an interrupt and executes the first instruction of the handler.
+    /// a fault is returned as an error if the policy halted the processor
+    pub fn step(&mut self) -> Result<StepOutcome, Fault> {
+        self.last_instr = None;
+        if self.halted {
+            return Ok(StepOutcome::Halted)
+        }
+        if self.history.is_none() && self.tracer.is_none() {
+            return self.execute_step()
+        }
+        // taken out for a moment so they can look at the processor
+        if let Some(mut h) = self.history.take() {
+            h.begin(self);
+            self.history = Some(h)
+        }
+        if let Some(mut t) = self.tracer.take() {
+            t.begin(self);
+            self.tracer = Some(t)
+        }
+        let outcome = self.execute_step();
+        if let Some(h) = &mut self.history {
+            match outcome {
+                // running out of cycles stops the step before anything is fetched
+                Err(Fault::CycleBudgetExceeded) => h.discard(),
+                _ => h.commit()
             }
-            _ => { // leading 1 = wide (in op op)
-                self.execute_wide(instr);
-                //self.program_counter += 3
+        }
+        if let Some(mut t) = self.tracer.take() {
+            t.end(self);
+            self.tracer = Some(t)
+        }
+        outcome
+    }
+    fn execute_step(&mut self) -> Result<StepOutcome, Fault> {
+        self.watch_hit = None;
+        let start_cycles = self.cycles;
+        self.begin_step()?;
+        let instr_pc = self.program_counter;
+        if let Some(t) = &mut self.tracer {
+            t.fetch(instr_pc, &self.memory, self.isa)
+        }
+        if let Some(c) = &mut self.coverage {
+            c.executed(instr_pc)
+        }
+        let d = decode(&self.memory, instr_pc, self.isa);
+        self.last_instr = Some((instr_pc, d.op));
+        let mut outcome = self.execute_decoded(&d, start_cycles)?;
+        if let StepOutcome::Continue = outcome {
+            if let Some(hit) = self.watch_hit.take() {
+                outcome = StepOutcome::Watchpoint(WatchHit { pc: instr_pc, ..hit })
+            }
+            else if let Some(i) = self.check_breakpoints() {
+                outcome = StepOutcome::Breakpoint(i)
             }
         }
-        if self.a == 0 {
-            self.status |= 0b10
+        Ok(outcome)
+    }
+    /// checks the cycle budget and takes an interrupt if there is one, returning whether it did
+    #[inline(always)]
+    fn begin_step(&mut self) -> Result<bool, Fault> {
+        if let Some(budget) = self.cycle_budget {
+            if self.cycles >= budget { // trapping would just run out again, so this always halts
+                self.halted = true;
+                self.fault_pc = self.program_counter;
+                self.fault = Some(Fault::CycleBudgetExceeded);
+                return Err(Fault::CycleBudgetExceeded)
+            }
+        }
+        if self.status & INTERRUPT_ENABLE != 0 && self.interrupt_pending() {
+            self.interrupt(IRQ_VECTOR);
+            self.cycles += INTERRUPT_CYCLES;
+            return Ok(true)
+        }
+        Ok(false)
+    }
+    /// executes the instruction at the pc, which `d` is the decoding of. `start_cycles` is the cycle count
+    /// before any interrupt this step took, so devices see the interrupt's cycles too
+    #[inline(always)]
+    fn execute_decoded(&mut self, d: &Decoded, start_cycles: u64) -> Result<StepOutcome, Fault> {
+        let instr_pc = self.program_counter;
+        let instr = d.op;
+        self.cycles += d.cost as u64;
+        let mut outcome = StepOutcome::Continue;
+        if instr == 23 {
+            self.program_counter = self.program_counter.wrapping_add(1);
+            outcome = StepOutcome::Break
         }
         else {
-            self.status &= !0b10
+            match instr & 0b1000_0000 { // msb determines instruction width
+                0 => { // leading zero = single width
+                    self.program_counter = self.program_counter.wrapping_add(1);
+                    self.execute_single_width(d);
+                }
+                _ => { // leading 1 = wide (in op op)
+                    self.execute_wide(d);
+                }
+            }
+            match self.isa {
+                Isa::V1 => self.set_flag(ZERO, self.a == 0), // after everything, which made it hard to use
+                Isa::V2 => {
+                    if let Some(v) = self.flag_register(instr) {
+                        self.set_zero_negative(v)
+                    }
+                }
+            }
         }
 
-        sleep(Duration::from_millis(po.clock_period));
+        if !self.bus.is_empty() {
+            self.bus.tick(self.cycles - start_cycles)
+        }
 
-        false
+        if let Some(f) = self.pending_fault.take() {
+            self.fault_pc = instr_pc;
+            match self.fault_policy {
+                FaultPolicy::Halt => {
+                    self.halted = true;
+                    self.fault = Some(f.clone());
+                    return Err(f)
+                }
+                FaultPolicy::Trap => {
+                    self.interrupt(FAULT_VECTOR);
+                    self.a = f.code();
+                    self.cycles += INTERRUPT_CYCLES;
+                    self.pending_fault = None; // the trap's own pushes don't count
+                    return Ok(StepOutcome::Trapped(f))
+                }
+                FaultPolicy::Ignore => {}
+            }
+        }
+
+        if self.halted {
+            outcome = StepOutcome::Halted
+        }
+        Ok(outcome)
     }
-    fn execute_single_width(&mut self, instr: u8) {
+
+    /// runs until the processor halts or stops at a `brk`, a breakpoint or a watchpoint, returning why like
+    /// `step`. when nothing needs to look at every instruction, straight line code is decoded once and run
+    /// from a cache, which is a lot faster but otherwise the same. with the jit feature, hot blocks run as native code
+    pub fn run_fast(&mut self) -> Result<StepOutcome, Fault> {
+        if self.history.is_some() || self.tracer.is_some() || self.coverage.is_some() || !self.breakpoints.is_empty()
+            || !self.watchpoints.is_empty() {
+            loop {
+                match self.step()? {
+                    StepOutcome::Continue|StepOutcome::Trapped(_) => {}
+                    o => return Ok(o)
+                }
+            }
+        }
+        self.block_cache.clear(); // memory could have been changed from outside since the last run
+        'blocks: loop {
+            if self.halted {
+                return Ok(StepOutcome::Halted)
+            }
+            let block = self.block_cache.get(&self.memory, self.program_counter, self.isa);
+            #[cfg(feature = "jit")]
+            let first = {
+                let n = self.run_native(&block);
+                if self.block_cache.dirty {
+                    self.block_cache.dirty = false;
+                    continue 'blocks
+                }
+                n
+            };
+            #[cfg(not(feature = "jit"))]
+            let first = 0;
+            for d in block.instrs[first..].iter() {
+                let start_cycles = self.cycles;
+                let interrupted = self.begin_step()?;
+                let outcome = if interrupted { // the handler isn't in this block
+                    let d = decode(&self.memory, self.program_counter, self.isa);
+                    self.execute_decoded(&d, start_cycles)?
+                }
+                else {
+                    self.execute_decoded(d, start_cycles)?
+                };
+                match outcome {
+                    StepOutcome::Continue => {}
+                    StepOutcome::Trapped(_) => continue 'blocks,
+                    o => return Ok(o)
+                }
+                if interrupted || self.block_cache.dirty { // the rest of this block might not be there any more
+                    self.block_cache.dirty = false;
+                    continue 'blocks
+                }
+            }
+        }
+    }
+
+    /// runs as much of the block as there's native code for, returning how many instructions that was. native
+    /// code only runs when nothing could happen between its instructions, so it ends up the same as interpreting
+    #[cfg(feature = "jit")]
+    fn run_native(&mut self, block: &Block) -> usize {
+        let pc = self.program_counter;
+        if !self.jit || self.status & INTERRUPT_ENABLE != 0 || !self.bus.is_empty() {
+            return 0
+        }
+        let native = match block.native(pc, self.isa) {
+            Some(n) => n,
+            None => return 0
+        };
+        if let Some(budget) = self.cycle_budget {
+            if self.cycles + native.costs[native.instrs] > budget {
+                return 0
+            }
+        }
+        let mut regs = Regs { a: self.a, b: self.b, x: self.x, status: self.status, sp: self.stack_pointer, pc, ..Regs::default() };
+        let n = native.run(&mut regs, &mut self.memory, self.block_cache.covered());
+        (self.a, self.b, self.x, self.status) = (regs.a, regs.b, regs.x, regs.status);
+        self.stack_pointer = regs.sp;
+        self.program_counter = regs.pc;
+        self.cycles += native.costs[n];
+        if regs.wrote != 0 { // wrote over code, maybe even this block
+            self.block_cache.written(regs.written)
+        }
+        n
+    }
+
+    /// breakpoints are checked once the pc lands on them, so resuming from one doesn't need to skip it
+    fn check_breakpoints(&mut self) -> Option<usize> {
+        let pc = self.program_counter;
+        let mut hit = None;
+        for i in 0..self.breakpoints.len() {
+            if self.breakpoints[i].address != pc {
+                continue
+            }
+            self.breakpoints[i].hits += 1;
+            let b = &self.breakpoints[i];
+            let stop = match &b.condition {
+                Some(c) => c.eval_with_hits(self, b.hits),
+                None => true
+            };
+            if stop && hit.is_none() {
+                hit = Some(i)
+            }
+        }
+        hit
+    }
+    /// only the first watchpoint hit in an instruction is reported
+    fn watch(&mut self, address: u16, value: u8, write: bool) {
+        for (i, w) in self.watchpoints.iter_mut().enumerate() {
+            if address < w.start || address > w.end || !(if write { w.write } else { w.read }) {
+                continue
+            }
+            w.hits += 1;
+            if self.watch_hit.is_none() {
+                self.watch_hit = Some(WatchHit { index: i, pc: 0, address, value, write }) // step fills in the pc
+            }
+        }
+    }
+
+    /// keeps emulated time in line with wall time. sleeping every instruction would be far too coarse,
+    /// so this only checks in every few milliseconds of emulated time
+    fn throttle(&mut self, hz: u64) {
+        let t = self.throttle.get_or_insert_with(|| Throttle {
+            start: Instant::now(),
+            start_cycles: self.cycles,
+            next_check: self.cycles
+        });
+        if self.cycles < t.next_check {
+            return
+        }
+        t.next_check = self.cycles + (hz / THROTTLE_CHECKS_PER_SECOND).max(1);
+        let emulated = Duration::from_secs_f64((self.cycles - t.start_cycles) as f64 / hz as f64);
+        let elapsed = t.start.elapsed();
+        if emulated > elapsed {
+            sleep(emulated - elapsed)
+        }
+    }
+    #[inline(always)]
+    fn execute_single_width(&mut self, d: &Decoded) {
+        let instr = d.op;
+        if !d.legal {
+            self.raise(Fault::IllegalOpcode(instr));
+            return
+        }
         match instr & 0b0111_1111 { 
             0 => {} // nop
             1 => self.halted = true, //hlt
@@ -180,6 +797,9 @@ impl Processor {
                 else {
                     self.status &= !1
                 }
+                if self.isa == Isa::V2 { // signed overflow: both the same sign, and the result isn't
+                    self.set_flag(OVERFLOW, !(self.a ^ self.b) & (self.a ^ result as u8) & 0x80 != 0)
+                }
                 self.a = result as u8
             }
             /*9 => { // adc
@@ -190,27 +810,16 @@ impl Processor {
                 }
                 self.a = result as u8
             }*/
-            /*10 => { // sub
-                if self.a >= self.b {
-                    self.a = self.a - self.b
-                }
-                else {
-                    self.a = self.b - self.a;
-                }
-            }*/
-            /*11 => { // sbc
-                let a = self.a as u16;
-                let b = self.b as u16;
-                if a >= b {
-                    self.a = (a - b) as u8;
-                    self.a += self.status & 1;
-                    self.status &= !1;
-                }
-                else {
-                    self.a = (b - a) as u8;
-                    self.a += self.status & 1;
-                }
-            }*/
+            10 => { // sub
+                let result = self.subtract(1);
+                self.set_flag(OVERFLOW, (self.a ^ self.b) & (self.a ^ result) & 0x80 != 0);
+                self.a = result
+            }
+            11 => { // sbc
+                let result = self.subtract(self.status & 1);
+                self.set_flag(OVERFLOW, (self.a ^ self.b) & (self.a ^ result) & 0x80 != 0);
+                self.a = result
+            }
             12 => { // lsr
                 let carry = self.status & 1;
                 if self.a & 128 != 0 { self.status |= 0b1 }
@@ -232,8 +841,9 @@ impl Processor {
                 self.status |= 1
             }
             16 => { // put
-                let _ = self.write_buffer.write(&[self.a]);
-                self.write_buffer.flush().unwrap()
+                if let Err(e) = self.write_buffer.write_all(&[self.a]).and_then(|_| self.write_buffer.flush()) {
+                    self.raise(Fault::Io(e.to_string()))
+                }
             }
             17 => { // psa
                 self.push(self.a)
@@ -248,10 +858,10 @@ impl Processor {
                 self.status = self.a
             }
             21 => { // ssp
-                self.stack_pointer = bytes_to_16(self.a, self.b) as usize
+                self.stack_pointer = bytes_to_16(self.a, self.b)
             }
             22 => { // gsp
-                (self.a, self.b) = u16_to_bytes(self.stack_pointer as u16)
+                (self.a, self.b) = u16_to_bytes(self.stack_pointer)
             }
             23 => { // brk
                 unreachable!() // debugging breakpoint
@@ -261,16 +871,20 @@ impl Processor {
                 //dbg!("rts ing");
                 let hi = self.pop();
                 let lo = self.pop();
-                self.program_counter = bytes_to_16(hi, lo) as usize
+                self.program_counter = bytes_to_16(hi, lo)
             }
             25 => { // lda const
-                self.a = self.memory[self.program_counter];
-                self.program_counter += 1
+                self.a = d.operand as u8;
+                self.program_counter = self.program_counter.wrapping_add(1)
             }
             26 => { // get
                 self.update_input_buf();
-                self.a = if self.get_buffer.len() > 0 {
-                    self.get_buffer.remove(0)
+                self.a = if !self.get_buffer.is_empty() {
+                    let byte = self.get_buffer.remove(0);
+                    if let Some(h) = &mut self.history {
+                        h.record_input(byte)
+                    }
+                    byte
                 }
                 else {0}
             }
@@ -293,23 +907,44 @@ impl Processor {
                     self.status |= 1
                 }
             }
-            _ => {} // nop
+            32 => { // sei
+                self.status |= INTERRUPT_ENABLE
+            }
+            33 => { // cli
+                self.status &= !INTERRUPT_ENABLE
+            }
+            34 => { // rti
+                self.status = self.pop();
+                let hi = self.pop();
+                let lo = self.pop();
+                self.program_counter = bytes_to_16(hi, lo)
+            }
+            35 => { // cmp
+                let result = self.subtract(1);
+                self.set_zero_negative(result)
+            }
+            _ => self.raise(Fault::IllegalOpcode(instr))
         }
     }
-    fn execute_wide(&mut self, instr: u8) {
-        let op1 = self.memory[self.program_counter + 1];
-        let op2 = self.memory[self.program_counter + 2];
-        //dbg!(addr);
-        self.program_counter += 3;
+    #[inline(always)]
+    fn execute_wide(&mut self, d: &Decoded) {
+        let instr = d.op;
+        let instr_pc = self.program_counter;
+        self.program_counter = self.program_counter.wrapping_add(3);
         //dbg!(self.program_counter);
+        if !d.legal {
+            self.raise(Fault::IllegalOpcode(instr));
+            return
+        }
 
         // for wide instructions:
-        // lda 1xxx_x000
-        // sta 1xxx_x001
-        // jmp 1xxx_x010
-        // jsr 1xxx_x011
-        // jez 1xxx_x100
-        // jgt 1xxx_x101
+        // lda 1x0x_x000
+        // sta 1x0x_x001
+        // jmp 1x0x_x010
+        // jsr 1x0x_x011
+        // jez 1x0x_x100
+        // jgt 1x0x_x101
+        // jnz, jcs, jcc, jmi, jpl, jvs, jvc 101x_x000 to 101x_x110
 
         // addressing modes:
         // direct 1xx0_0xxx
@@ -333,52 +968,598 @@ impl Processor {
         } as usize;*/
 
         let mut addr = if instr & 0b0001_0000 != 0 { // work smarter not harder
-            let tmp_addr = bytes_to_16(op1, op2);
+            let tmp_addr = d.operand;
             let hb = self.memory[tmp_addr as usize];
-            let lb = self.memory[(tmp_addr + 1) as usize];
+            let lb = self.memory[tmp_addr.wrapping_add(1) as usize];
             bytes_to_16(hb, lb)
         }
         else {
-            bytes_to_16(op1, op2)
-        } as usize;
+            d.operand
+        };
         if instr &0b1000 != 0 {
-            addr += self.x as usize
+            addr = addr.wrapping_add(self.x as u16)
+        }
+        if let Some(t) = &mut self.tracer {
+            t.effective_address(addr)
+        }
+
+        if instr & 0b0010_0000 != 0 { // branches on a flag
+            let taken = match instr & 0b111 {
+                0b000 => self.status & ZERO == 0, // jnz
+                0b001 => self.status & CARRY != 0, // jcs
+                0b010 => self.status & CARRY == 0, // jcc
+                0b011 => self.status & NEGATIVE != 0, // jmi
+                0b100 => self.status & NEGATIVE == 0, // jpl
+                0b101 => self.status & OVERFLOW != 0, // jvs
+                0b110 => self.status & OVERFLOW == 0, // jvc
+                _ => {
+                    self.raise(Fault::IllegalOpcode(instr));
+                    return
+                }
+            };
+            if let Some(c) = &mut self.coverage {
+                c.branch(instr_pc, taken)
+            }
+            if taken {
+                self.program_counter = addr
+            }
+            return
         }
 
         match instr & 0b0000_0111 {
-            0b000 => self.a = self.memory[addr],
-            0b001 => self.memory[addr] = self.a,
+            0b000 => self.a = self.load(addr),
+            0b001 => self.store(addr, self.a),
             0b010 => self.program_counter = addr,
             0b011 => {
                 //dbg!("jsr ing");
-                let (hb, lb) = u16_to_bytes((self.program_counter) as u16);
+                let (hb, lb) = u16_to_bytes(self.program_counter);
                 self.push(lb);
                 self.push(hb);
                 self.program_counter = addr
             }
             0b100 => {
-                if self.status & 0b10 != 0 {
+                let taken = self.status & ZERO != 0;
+                if let Some(c) = &mut self.coverage {
+                    c.branch(instr_pc, taken)
+                }
+                if taken {
                     self.program_counter = addr
                 }
             }
             0b101 => {
-                if self.a > self.b {
+                let taken = self.a > self.b;
+                if let Some(c) = &mut self.coverage {
+                    c.branch(instr_pc, taken)
+                }
+                if taken {
                     self.program_counter = addr
                 }
             }
-            _ => {} // nop
+            _ => self.raise(Fault::IllegalOpcode(instr))
         }
     }
 
+    /// the register an instruction sets the zero and negative flags from, if it sets them. cmp, which
+    /// doesn't change a register, sets them itself
+    #[inline(always)]
+    fn flag_register(&self, instr: u8) -> Option<u8> {
+        if instr & 0b1000_0000 != 0 {
+            return if instr & 0b1110_0111 == 0b1000_0000 { Some(self.a) } else { None } // lda
+        }
+        match instr {
+            3 => Some(self.b), // tab
+            4|6|7 => Some(self.x), // tax, inc, dec
+            2|5|8|10|11|12|13|18|25|26|27..=31 => Some(self.a), // everything else that changes a, except gst and gsp
+            _ => None
+        }
+    }
+    #[inline(always)]
+    fn set_zero_negative(&mut self, v: u8) {
+        self.set_flag(ZERO, v == 0);
+        self.set_flag(NEGATIVE, v & 0x80 != 0)
+    }
+    #[inline(always)]
+    fn set_flag(&mut self, flag: u8, on: bool) {
+        self.status = (self.status & !flag) | (flag * on as u8) // no branch, since it runs every instruction
+    }
+
+    /// a - b - (1 - carry_in), setting the carry flag if nothing was borrowed, like the 6502
+    fn subtract(&mut self, carry_in: u8) -> u8 {
+        let result = self.a as i16 - self.b as i16 - (1 - carry_in as i16);
+        if result >= 0 {
+            self.status |= 1
+        }
+        else {
+            self.status &= !1
+        }
+        result as u8
+    }
+
+    /// irqs are level triggered: a device holding its line up, or input waiting in the buffer
+    fn interrupt_pending(&mut self) -> bool {
+        if !self.bus.is_empty() && self.bus.irq() {
+            return true
+        }
+        self.update_input_buf();
+        !self.get_buffer.is_empty()
+    }
+    /// saves the pc and status like jsr + psa, then jumps through the vector with interrupts disabled
+    fn interrupt(&mut self, vector: usize) {
+        let (hb, lb) = u16_to_bytes(self.program_counter);
+        self.push(lb);
+        self.push(hb);
+        self.push(self.status);
+        self.status &= !INTERRUPT_ENABLE;
+        self.program_counter = bytes_to_16(self.memory[vector], self.memory[vector + 1])
+    }
+
+    fn load(&mut self, addr: u16) -> u8 {
+        let v = match self.bus.read(addr) {
+            Ok(Some(v)) => v,
+            Ok(None) => self.memory[addr as usize],
+            Err(f) => {
+                self.raise(f);
+                0
+            }
+        };
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, v, false)
+        }
+        v
+    }
+    fn store(&mut self, addr: u16, val: u8) {
+        if !self.watchpoints.is_empty() {
+            self.watch(addr, val, true)
+        }
+        if let Some(t) = &mut self.tracer {
+            t.write(addr, val)
+        }
+        match self.bus.write(addr, val) {
+            Ok(true) => {}
+            Ok(false) => self.write_ram(addr, val),
+            Err(f) => self.raise(f)
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) {
+        if let Some(h) = &mut self.history {
+            h.record_write(addr, self.memory[addr as usize], val)
+        }
+        self.block_cache.written(addr);
+        self.memory[addr as usize] = val
+    }
+
     fn push(&mut self, byte: u8) {
         //println!("pushing {:x}", byte);
-        self.memory[self.stack_pointer] = byte;
-        self.stack_pointer += 1
+        if !self.watchpoints.is_empty() {
+            self.watch(self.stack_pointer, byte, true)
+        }
+        if let Some(t) = &mut self.tracer {
+            t.write(self.stack_pointer, byte)
+        }
+        self.write_ram(self.stack_pointer, byte);
+        if self.stack_pointer == 0xffff {
+            self.raise(Fault::StackOverflow)
+        }
+        self.stack_pointer = self.stack_pointer.wrapping_add(1)
     }
     fn pop(&mut self) -> u8 {
-        self.stack_pointer -= 1;
-        let byte = self.memory[self.stack_pointer];
+        if self.stack_pointer == 0 {
+            self.raise(Fault::StackUnderflow)
+        }
+        self.stack_pointer = self.stack_pointer.wrapping_sub(1);
+        let byte = self.memory[self.stack_pointer as usize];
+        if !self.watchpoints.is_empty() {
+            self.watch(self.stack_pointer, byte, false)
+        }
         //println!("popping {:x}", byte);
         byte
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::SharedBuffer;
+    use std::io;
+    use std::io::Cursor;
+    use crate::assembler::assemble_with_isa;
+
+    struct Broken;
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> { Err(io::Error::new(ErrorKind::BrokenPipe, "broken")) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    fn run_source(src: &str, p: ProcessorBuilder) -> Processor {
+        let (prog, _) = assemble_with_isa(src, p.isa).unwrap();
+        let mut p = p.memory(&prog).build();
+        let po = Options::default();
+        let mut steps = 0;
+        while !p.halted && steps < 100_000 {
+            p.execute(false, &po);
+            steps += 1
+        }
+        p
+    }
+
+    const ECHO_UNTIL_EMPTY: &str = "
+LOOP:   gbf
+        jez END
+        get
+        put
+        jmp LOOP
+END:    hlt";
+
+    /// runs a program like `run_fast` with the jit on, but translating every block as soon as it's reached
+    /// rather than once it's hot, so short programs run natively too. returns how many instructions did
+    #[cfg(feature = "jit")]
+    fn run_translated(src: &str, p: ProcessorBuilder) -> (Processor, usize) {
+        use crate::jit::Native;
+        let (prog, _) = assemble_with_isa(src, p.isa).unwrap();
+        let mut p = p.memory(&prog).build();
+        let mut cache = BlockCache::default();
+        let mut native = 0;
+        let mut steps = 0;
+        while !p.halted && steps < 100_000 {
+            if p.status & INTERRUPT_ENABLE == 0 {
+                cache.clear(); // only this block counts as code, which is enough to catch it writing over itself
+                let block = cache.get(&p.memory, p.program_counter, p.isa);
+                if let Some(code) = Native::compile(&block.instrs, p.program_counter, p.isa) {
+                    let mut regs = Regs { a: p.a, b: p.b, x: p.x, status: p.status, sp: p.stack_pointer,
+                        pc: p.program_counter, ..Regs::default() };
+                    let n = code.run(&mut regs, &mut p.memory, cache.covered());
+                    (p.a, p.b, p.x, p.status) = (regs.a, regs.b, regs.x, regs.status);
+                    (p.stack_pointer, p.program_counter) = (regs.sp, regs.pc);
+                    p.cycles += code.costs[n];
+                    native += n;
+                    steps += n
+                }
+            }
+            let _ = p.step(); // whatever stopped the native code
+            steps += 1
+        }
+        (p, native)
+    }
+
+    #[test]
+    fn in_memory_io() {
+        let out = SharedBuffer::default();
+        let p = run_source(ECHO_UNTIL_EMPTY, ProcessorBuilder::new().output(out.clone()).input(Cursor::new(b"hello".to_vec())));
+        assert!(p.fault.is_none());
+        assert_eq!(out.contents(), b"hello");
+    }
+
+    #[test]
+    fn output_error_halts() {
+        let p = run_source(ECHO_UNTIL_EMPTY, ProcessorBuilder::new().output(Broken).input(Cursor::new(b"hi".to_vec())));
+        assert!(p.halted);
+        assert_eq!(p.fault, Some(Fault::Io(String::from("broken"))));
+        assert_eq!(p.program_counter, 6) // just after the put
+    }
+
+    #[test]
+    fn input_interrupt() {
+        let src = "
+        lda #0xf0
+        ssp
+        sei
+LOOP:   jmp LOOP
+
+HANDLER:
+        get
+        put
+        gbf
+        jez DONE
+        rti
+DONE:   hlt
+
+        org 0xfffe
+        dat 0x00
+        dat 0x07";
+        let out = SharedBuffer::default();
+        let p = run_source(src, ProcessorBuilder::new().output(out.clone()).input(Cursor::new(b"ab".to_vec())));
+        assert_eq!(out.contents(), b"ab");
+        assert_eq!(p.stack_pointer, 0xf003); // pc and status from the second interrupt
+        assert_eq!(p.memory[0xf002] & INTERRUPT_ENABLE, INTERRUPT_ENABLE);
+        assert_eq!(p.status & INTERRUPT_ENABLE, 0)
+    }
+
+    fn step_at(pc: u16, code: &[u8]) -> Processor {
+        let mut p = ProcessorBuilder::new().output(io::sink()).input(io::empty()).build();
+        for (i, b) in code.iter().enumerate() {
+            p.memory[pc.wrapping_add(i as u16) as usize] = *b
+        }
+        p.program_counter = pc;
+        p
+    }
+
+    #[test]
+    fn pc_wraps() {
+        let mut p = step_at(0xffff, &[0]); // nop
+        p.execute(false, &Options::default());
+        assert_eq!(p.program_counter, 0);
+
+        let mut p = step_at(0xffff, &[25, 0x42]); // lda #, operand at 0x0000
+        p.execute(false, &Options::default());
+        assert_eq!((p.a, p.program_counter), (0x42, 1));
+
+        let mut p = step_at(0xfffe, &[0b1000_0010, 0x12, 0x34]); // jmp, operands straddle the end
+        p.execute(false, &Options::default());
+        assert_eq!(p.program_counter, 0x1234);
+
+        let mut p = step_at(0xfffe, &[0b1000_0000, 0x00, 0x10]); // lda, falls through to 0x0001
+        p.memory[0x10] = 7;
+        p.execute(false, &Options::default());
+        assert_eq!((p.a, p.program_counter), (7, 1));
+    }
+
+    #[test]
+    fn effective_addresses_wrap() {
+        let mut p = step_at(0, &[0b1000_1000, 0xff, 0xf0]); // lda 0xfff0,x
+        p.x = 0x20;
+        p.memory[0x0010] = 9;
+        p.execute(false, &Options::default());
+        assert_eq!(p.a, 9);
+
+        let mut p = step_at(0x10, &[0b1001_0000, 0xff, 0xff]); // lda (0xffff), pointer straddles the end
+        p.memory[0xffff] = 0x03;
+        p.memory[0x0000] = 0x90;
+        p.memory[0x0390] = 5;
+        p.execute(false, &Options::default());
+        assert_eq!(p.a, 5);
+
+        let mut p = step_at(0x10, &[0b1001_1001, 0x00, 0x20]); // sta (0x0020),x
+        p.memory[0x20] = 0xff;
+        p.memory[0x21] = 0xfe;
+        p.x = 3;
+        p.a = 1;
+        p.execute(false, &Options::default());
+        assert_eq!(p.memory[0x0001], 1);
+    }
+
+    #[test]
+    fn stack_wraps() {
+        let mut p = step_at(0, &[17]); // psa
+        p.fault_policy = FaultPolicy::Ignore;
+        p.stack_pointer = 0xffff;
+        p.a = 3;
+        p.execute(false, &Options::default());
+        assert_eq!((p.stack_pointer, p.memory[0xffff]), (0, 3));
+
+        let mut p = step_at(0x10, &[18]); // ppa
+        p.fault_policy = FaultPolicy::Ignore;
+        p.memory[0xffff] = 4;
+        p.execute(false, &Options::default());
+        assert_eq!((p.stack_pointer, p.a), (0xffff, 4));
+
+        let mut p = step_at(0xfffd, &[0b1000_0011, 0x12, 0x34]); // jsr, return address wraps to 0
+        p.stack_pointer = 0x100;
+        p.execute(false, &Options::default());
+        assert_eq!((p.memory[0x100], p.memory[0x101], p.program_counter), (0, 0, 0x1234));
+
+        let mut p = step_at(0x10, &[24]); // rts
+        p.fault_policy = FaultPolicy::Ignore;
+        p.memory[0xffff] = 0xab; // hi
+        p.memory[0xfffe] = 0xcd; // lo
+        p.stack_pointer = 0;
+        p.execute(false, &Options::default());
+        assert_eq!((p.program_counter, p.stack_pointer), (0xabcd, 0xfffe));
+    }
+
+    #[test]
+    fn faults() {
+        let mut p = step_at(0x10, &[18]); // ppa
+        assert_eq!(p.step().err(), Some(Fault::StackUnderflow));
+        assert!(p.halted);
+        assert_eq!((p.fault.clone(), p.fault_pc), (Some(Fault::StackUnderflow), 0x10));
+        assert!(matches!(p.step(), Ok(StepOutcome::Halted)));
+
+        for op in [9, 36, 0x7f, 0b1000_0110, 0b1001_1111] {
+            let mut p = step_at(0, &[op, 0, 0]);
+            assert_eq!(p.step().err(), Some(Fault::IllegalOpcode(op)));
+        }
+
+        let mut p = step_at(0x10, &[17]); // psa
+        p.stack_pointer = 0xffff;
+        assert_eq!(p.step().err(), Some(Fault::StackOverflow));
+
+        let mut p = step_at(0, &[0b1000_0000, 0x80, 0x00]); // lda 0x8000
+        p.bus.unmap(0x8000, 0x80ff).unwrap();
+        assert_eq!(p.step().err(), Some(Fault::Unmapped(0x8000)));
+
+        let mut p = step_at(0, &[0b1000_0010, 0, 0]); // jmp 0
+        p.cycle_budget = Some(30);
+        while p.step().is_ok() {}
+        assert_eq!((p.fault.clone(), p.cycles), (Some(Fault::CycleBudgetExceeded), 30));
+    }
+
+    #[test]
+    fn fault_policies() {
+        let mut p = step_at(0x10, &[9]);
+        p.fault_policy = FaultPolicy::Ignore;
+        assert!(matches!(p.step(), Ok(StepOutcome::Continue)));
+        assert_eq!(p.program_counter, 0x11);
+
+        let mut p = step_at(0x10, &[9]);
+        p.fault_policy = FaultPolicy::Trap;
+        p.stack_pointer = 0x200;
+        p.memory[FAULT_VECTOR] = 0x12;
+        p.memory[FAULT_VECTOR + 1] = 0x34;
+        assert!(matches!(p.step(), Ok(StepOutcome::Trapped(Fault::IllegalOpcode(9)))));
+        assert_eq!((p.program_counter, p.a, p.stack_pointer, p.halted), (0x1234, 3, 0x203, false));
+        assert_eq!((p.memory[0x200], p.memory[0x201]), (0x11, 0x00)); // return address after the bad opcode
+    }
+
+    #[test]
+    fn subtraction_flags() {
+        // the reference model: a - b - borrow in signed and unsigned arithmetic, where the carry flag means no borrow
+        let model = |a: u8, b: u8, carry: bool| {
+            let borrow = if carry { 0 } else { 1 };
+            let diff = a as i16 - b as i16 - borrow;
+            let signed = a as i8 as i16 - b as i8 as i16 - borrow;
+            (diff as u8, diff >= 0, !(-128..=127).contains(&signed))
+        };
+        let mut p = step_at(0, &[10, 11, 35]); // sub, sbc, cmp
+        p.isa = Isa::V2;
+        for a in 0..=255 {
+            for b in 0..=255 {
+                for carry in [false, true] {
+                    for op in 0..3 {
+                        p.program_counter = op;
+                        p.a = a;
+                        p.b = b;
+                        // other flags are left alone, including overflow by cmp
+                        let other = if a & 1 != 0 { INTERRUPT_ENABLE } else { 0 } | if b & 1 != 0 && op == 2 { OVERFLOW } else { 0 };
+                        p.status = other | carry as u8;
+                        p.step().unwrap();
+                        let (result, no_borrow, overflow) = match op {
+                            1 => model(a, b, carry),
+                            _ => model(a, b, true) // sub and cmp ignore the carry
+                        };
+                        let expected_a = if op == 2 { a } else { result }; // cmp doesn't change a
+                        assert_eq!((p.a, p.b), (expected_a, b), "op {} a {} b {} carry {}", op, a, b, carry);
+                        let mut expected = other | no_borrow as u8;
+                        if result == 0 {
+                            expected |= ZERO
+                        }
+                        if result & 0x80 != 0 {
+                            expected |= NEGATIVE
+                        }
+                        if overflow && op != 2 {
+                            expected |= OVERFLOW
+                        }
+                        assert_eq!(p.status, expected, "op {} a {} b {} carry {}", op, a, b, carry);
+                    }
+                }
+            }
+        }
+    }
+
+    // add overflows into the sign bit, and the flags it set survive the jumps
+    const FLAG_BRANCHES: &str = "
+        lda #0x7f
+        tab
+        lda #1
+        clc
+        add
+        jmp NEXT
+NEXT:   nop
+        jvc FAIL
+        jpl FAIL
+        jez FAIL
+        jcs FAIL
+        lda #0
+        tax
+        inc
+        jez FAIL
+        jnz NONZERO
+        jmp FAIL
+NONZERO: lda #0xff
+        tab
+        lda #1
+        add
+        jcc FAIL
+        jmi FAIL
+        jvs FAIL
+        jnz FAIL
+        gst
+        sta 0x0300
+        hlt
+FAIL:   lda #0x01
+        sta 0x0301
+        hlt";
+
+    #[test]
+    fn flags() {
+        let p = run_source(FLAG_BRANCHES, ProcessorBuilder::new().output(io::sink()).input(io::empty()).isa(Isa::V2));
+        assert_eq!(p.memory[0x301], 0);
+        assert_eq!(p.memory[0x300], CARRY | ZERO); // 1 + 0xff carries to exactly 0
+        let mut p = step_at(0, &[19, 25, 0b1100_0001, 20]); // gst; lda #0xc1; sst
+        p.isa = Isa::V2;
+        p.status = INTERRUPT_ENABLE;
+        for _ in 0..3 {
+            p.step().unwrap();
+        }
+        assert_eq!((p.a, p.status), (0b1100_0001, 0b1100_0001));
+        for op in [0b1010_0111, 0b1100_0000] {
+            let mut p = step_at(0, &[op, 0, 0]);
+            p.isa = Isa::V2;
+            assert_eq!(p.step().err(), Some(Fault::IllegalOpcode(op)));
+        }
+
+        // v1 sets the zero flag from a after every instruction, and doesn't have the new instructions
+        let mut p = step_at(0, &[6, 8, 0b1010_0000, 0, 0]); // inc; add; jnz 0
+        p.x = 0xff;
+        p.a = 1;
+        p.b = 0x7f;
+        p.step().unwrap();
+        assert_eq!(p.status, 0);
+        p.step().unwrap();
+        assert_eq!((p.a, p.status), (0x80, 0));
+        assert_eq!(p.step().err(), Some(Fault::IllegalOpcode(0b1010_0000)));
+        let mut p = step_at(0, &[6]);
+        p.isa = Isa::V2;
+        p.x = 0xff;
+        p.a = 1;
+        p.step().unwrap();
+        assert_eq!(p.status, ZERO);
+    }
+
+    const CYCLE_COUNTING: &str = "
+        lda #1      ; 2
+        sta 0x0300  ; 4
+        lda (0x0300),x ; 7
+        hlt         ; 1";
+
+    #[test]
+    fn cycle_counting() {
+        let p = run_source(CYCLE_COUNTING, ProcessorBuilder::new());
+        assert_eq!(p.cycles, 14);
+        assert!(cycle_cost(0b1001_1000) > cycle_cost(0b1000_0000))
+    }
+
+    /// the programs above end up the same with the jit running everything it can translate
+    #[cfg(feature = "jit")]
+    #[test]
+    fn translated() {
+        let cases = [(FLAG_BRANCHES, Isa::V2, ""), (CYCLE_COUNTING, Isa::V1, ""), (CYCLE_COUNTING, Isa::V2, ""),
+            (ECHO_UNTIL_EMPTY, Isa::V1, "hello"), (ECHO_UNTIL_EMPTY, Isa::V2, "hello")];
+        for (src, isa, input) in cases {
+            let builder = || ProcessorBuilder::new().isa(isa).output(io::sink()).input(Cursor::new(input.as_bytes().to_vec()));
+            let (p, native) = run_translated(src, builder());
+            assert!(native > 0);
+            assert!(p.halted && p.fault.is_none());
+            assert_eq!(p.snapshot(), run_source(src, builder()).snapshot(), "{:?} {}", isa, src);
+        }
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        // psa; ppa; sta 0x0300; lda 0x0300; nop
+        let mut p = step_at(0x10, &[17, 18, 0b1000_0001, 0x03, 0x00, 0b1000_0000, 0x03, 0x00, 0]);
+        p.stack_pointer = 0x200;
+        p.a = 7;
+        p.watchpoints.push(Watchpoint::new(0x200, 0x200, false, true));
+        p.watchpoints.push(Watchpoint::new(0x2ff, 0x300, true, false));
+        let hit = |index, pc, address, write| WatchHit { index, pc, address, value: 7, write };
+        assert_eq!(p.step().ok().and_then(|o| match o { StepOutcome::Watchpoint(h) => Some(h), _ => None }), Some(hit(0, 0x10, 0x200, true)));
+        assert!(matches!(p.step(), Ok(StepOutcome::Continue))); // pops are reads
+        assert!(matches!(p.step(), Ok(StepOutcome::Continue)));
+        assert_eq!(p.step().ok().and_then(|o| match o { StepOutcome::Watchpoint(h) => Some(h), _ => None }), Some(hit(1, 0x15, 0x300, false)));
+        assert_eq!((p.watchpoints[0].hits, p.watchpoints[1].hits), (1, 1));
+
+        let mut p = step_at(0x10, &[6, 0b1000_0010, 0x00, 0x10]); // inc; jmp 0x10
+        let cond = Condition::parse("x == 3", &|_| None).unwrap();
+        p.breakpoints.push(Breakpoint::new(0x10, Some(cond)));
+        p.breakpoints.push(Breakpoint::new(0x10, Some(Condition::parse("hits == 5", &|_| None).unwrap())));
+        let mut stops = Vec::new();
+        for _ in 0..12 {
+            if let Ok(StepOutcome::Breakpoint(i)) = p.step() {
+                stops.push((i, p.x))
+            }
+        }
+        assert_eq!(stops, [(0, 3), (1, 5)]);
+        assert_eq!(p.breakpoints[0].hits, 6);
+    }
+}
@@ -0,0 +1,344 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{read_to_string, write};
+
+use crate::assembler::{assemble_with_isa, DebugInfo};
+use crate::bus::Bus;
+use crate::disassembler::disassemble;
+use crate::isa::Isa;
+use crate::processor::{Fault, Processor, ProcessorBuilder, StepOutcome};
+use crate::utils::Options;
+
+/// programs that never return from their routines would otherwise grow the call stack forever
+const MAX_DEPTH: usize = 256;
+/// how many addresses the hot address report shows
+const HOT_ADDRESSES: usize = 20;
+
+#[derive(Default, Clone, Copy)]
+pub struct Counts {
+    pub instructions: u64,
+    pub cycles: u64
+}
+impl Counts {
+    fn add(&mut self, cycles: u64) {
+        self.instructions += 1;
+        self.cycles += cycles
+    }
+}
+
+/// counts what the processor executes, by address and by call stack.
+///
+/// call stacks are made of `jsr` targets and popped by `rts`, so a routine that returns some other way
+/// stays on the stack. interrupts aren't frames, so handlers count towards whatever they interrupted
+#[derive(Default)]
+pub struct Profiler {
+    pub addresses: BTreeMap<u16, Counts>,
+    pub stacks: HashMap<Vec<u16>, Counts>, // keyed by the jsr targets from the outside in
+    pub calls: HashMap<(Option<u16>, u16), u64>, // (caller, callee), where the caller is None at the top level
+    stack: Vec<u16>,
+    unpushed: usize // calls made past MAX_DEPTH, whose rts mustn't pop a frame
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// steps the processor and records the instruction
+    pub fn step(&mut self, p: &mut Processor) -> Result<StepOutcome, Fault> {
+        let start_cycles = p.cycles;
+        let outcome = p.step();
+        let cycles = p.cycles - start_cycles;
+        // a step that takes an interrupt runs the handler's first instruction, not the one under the old pc
+        let (pc, op) = match p.last_instr {
+            Some(i) => i,
+            None => return outcome
+        };
+
+        self.addresses.entry(pc).or_default().add(cycles);
+        self.stacks.entry(self.stack.clone()).or_default().add(cycles);
+
+        if let Ok(StepOutcome::Continue) = outcome {
+            if op & 0b1110_0111 == 0b1000_0011 { // any jsr
+                *self.calls.entry((self.stack.last().copied(), p.program_counter)).or_default() += 1;
+                if self.stack.len() < MAX_DEPTH {
+                    self.stack.push(p.program_counter)
+                }
+                else {
+                    self.unpushed += 1
+                }
+            }
+            else if op == 24 { // rts
+                if self.unpushed > 0 {
+                    self.unpushed -= 1
+                }
+                else {
+                    self.stack.pop();
+                }
+            }
+        }
+        outcome
+    }
+
+    pub fn total(&self) -> Counts {
+        let mut total = Counts::default();
+        for c in self.addresses.values() {
+            total.instructions += c.instructions;
+            total.cycles += c.cycles
+        }
+        total
+    }
+
+    /// flat by routine, where an address belongs to the closest label before it, then the hottest addresses
+    /// and the call tree
+    pub fn report(&self, debug: &DebugInfo, memory: &[u8], isa: Isa) -> String {
+        let total = self.total();
+        let percent = |c: u64| if total.cycles == 0 { 0.0 } else { c as f64 * 100.0 / total.cycles as f64 };
+        let mut ret = format!("{} instructions, {} cycles\n\n", total.instructions, total.cycles);
+
+        let mut routines: HashMap<String, Counts> = HashMap::new();
+        for (addr, c) in &self.addresses {
+            let r = routines.entry(routine(debug, *addr)).or_default();
+            r.instructions += c.instructions;
+            r.cycles += c.cycles
+        }
+        let mut routines = routines.into_iter().collect::<Vec<(String, Counts)>>();
+        routines.sort_by(|(n1, c1), (n2, c2)| c2.cycles.cmp(&c1.cycles).then(n1.cmp(n2)));
+        ret.push_str("flat profile\n      cycles       %  instructions  routine\n");
+        for (name, c) in &routines {
+            ret.push_str(&format!("{:>12} {:>6.2}% {:>13}  {}\n", c.cycles, percent(c.cycles), c.instructions, name))
+        }
+
+        let mut hot = self.addresses.iter().collect::<Vec<(&u16, &Counts)>>();
+        hot.sort_by(|(a1, c1), (a2, c2)| c2.cycles.cmp(&c1.cycles).then(a1.cmp(a2)));
+        ret.push_str("\nhot addresses\n      cycles       %  instructions  address\n");
+        for (addr, c) in hot.iter().take(HOT_ADDRESSES) {
+            let (instr, _) = disassemble(memory, **addr as usize, isa, Some(&debug.labels));
+            let mut place = format!("0x{:0>4x}", addr);
+            if let Some(s) = debug.symbolize(**addr as usize) {
+                place.push_str(&format!(" <{}>", s))
+            }
+            if let Some(l) = debug.line_at(**addr as usize) {
+                place.push_str(&format!(" (line {})", l))
+            }
+            ret.push_str(&format!("{:>12} {:>6.2}% {:>13}  {}: {}\n", c.cycles, percent(c.cycles), c.instructions, place, instr))
+        }
+
+        ret.push_str("\ncall tree\n  total cycles       %   self cycles  calls  routine\n");
+        self.tree(debug, &[], &mut ret, &percent);
+        ret
+    }
+
+    /// cycles spent in this frame and everything it called
+    fn inclusive(&self, frames: &[u16]) -> Counts {
+        let mut ret = Counts::default();
+        for (stack, c) in &self.stacks {
+            if stack.starts_with(frames) {
+                ret.instructions += c.instructions;
+                ret.cycles += c.cycles
+            }
+        }
+        ret
+    }
+    fn tree(&self, debug: &DebugInfo, frames: &[u16], ret: &mut String, percent: &dyn Fn(u64) -> f64) {
+        let total = self.inclusive(frames);
+        let own = self.stacks.get(frames).copied().unwrap_or_default();
+        let (name, calls) = match frames.split_last() {
+            Some((callee, callers)) => (routine(debug, *callee), self.calls.get(&(callers.last().copied(), *callee)).copied().unwrap_or(0)),
+            None => (String::from("(top)"), 0)
+        };
+        ret.push_str(&format!("{:>14} {:>6.2}% {:>13} {:>6}  {}{}\n",
+            total.cycles, percent(total.cycles), own.cycles, calls, "  ".repeat(frames.len()), name));
+
+        let mut children = self.stacks.keys()
+            .filter(|s| s.len() > frames.len() && s.starts_with(frames))
+            .map(|s| s[frames.len()])
+            .collect::<Vec<u16>>();
+        children.sort();
+        children.dedup();
+        let mut children = children.into_iter().map(|c| {
+            let mut f = frames.to_vec();
+            f.push(c);
+            let cycles = self.inclusive(&f).cycles;
+            (f, cycles)
+        }).collect::<Vec<(Vec<u16>, u64)>>();
+        children.sort_by(|(f1, c1), (f2, c2)| c2.cmp(c1).then(f1.cmp(f2)));
+        for (f, _) in children {
+            self.tree(debug, &f, ret, percent)
+        }
+    }
+
+    /// one line per call stack with the cycles spent in it, for flamegraph.pl and friends
+    pub fn folded(&self, debug: &DebugInfo) -> String {
+        let mut lines = self.stacks.iter().filter(|(_, c)| c.cycles != 0).map(|(stack, c)| {
+            let mut names = vec![String::from("(top)")];
+            names.extend(stack.iter().map(|f| routine(debug, *f)));
+            format!("{} {}", names.join(";"), c.cycles)
+        }).collect::<Vec<String>>();
+        lines.sort();
+        let mut ret = lines.join("\n");
+        ret.push('\n');
+        ret
+    }
+}
+
+/// the closest label at or before an address. code before the first label is usually the main program
+fn routine(debug: &DebugInfo, addr: u16) -> String {
+    match debug.symbolize(addr as usize) {
+        Some(s) => match s.split_once('+') {
+            Some((label, _)) => String::from(label),
+            None => s
+        },
+        None => String::from("(unlabelled)")
+    }
+}
+
+/// `-P`: runs a source file to completion and prints a profile
+pub fn run_profile(po: &Options) -> Result<(), String> {
+    let program = match read_to_string(&po.path) {
+        Ok(s) => s,
+        Err(_) => return Err(format!("unable to read file {}", po.path))
+    };
+    let isa = Isa::from_options(po)?.unwrap_or_default();
+    let (prog, debug) = assemble_with_isa(&program, isa)?;
+    let mut p = ProcessorBuilder::new().memory(&prog).bus(Bus::from_options(po)?).isa(isa).build();
+    p.start_at_entry(&debug);
+    p.fault_policy = po.fault_policy;
+    p.cycle_budget = if po.max_cycles == 0 { None } else { Some(po.max_cycles) };
+
+    let mut profiler = Profiler::new();
+    while !p.halted {
+        let _ = profiler.step(&mut p);
+    }
+    println!("\n{}", profiler.report(&debug, &p.memory, p.isa));
+    if let Some(f) = &p.fault {
+        println!("fault at 0x{:0>4x}: {}", p.fault_pc, f)
+    }
+    if !po.folded.is_empty() {
+        if let Err(e) = write(&po.folded, profiler.folded(&debug)) {
+            return Err(format!("unable to write folded stacks to {}: {}", po.folded, e))
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use crate::assembler::assemble_with_debug;
+
+    const PROGRAM: &str = "
+        lda #0xf0
+        ssp
+        jsr TWICE
+        jsr ONCE
+        hlt
+TWICE:  jsr ONCE
+        jsr ONCE
+        rts
+ONCE:   lda #0
+        tax
+LOOP:   inc
+        txa
+        swp
+        lda #5
+        jgt LOOP
+        rts";
+
+    #[test]
+    fn profile() {
+        let (prog, debug) = assemble_with_debug(PROGRAM).unwrap();
+        let mut p = ProcessorBuilder::new().memory(&prog).output(io::sink()).input(io::empty()).build();
+        let mut profiler = Profiler::new();
+        while !p.halted {
+            profiler.step(&mut p).unwrap();
+        }
+        let total = profiler.total();
+        assert_eq!(total.cycles, p.cycles);
+
+        // each call to ONCE goes round LOOP 5 times
+        let looping = debug.labels["LOOP"] as u16;
+        assert_eq!(profiler.addresses[&looping].instructions, 15);
+        let twice = debug.labels["TWICE"] as u16;
+        let once_addr = debug.labels["ONCE"] as u16;
+        assert_eq!(profiler.calls[&(Some(twice), once_addr)], 2);
+        assert_eq!(profiler.calls[&(None, once_addr)], 1);
+        assert_eq!(profiler.stacks[&vec![twice, once_addr]].instructions, 2 * (2 + 5 * 5 + 1));
+
+        let folded = profiler.folded(&debug);
+        let lines = folded.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("(top) "));
+        assert!(lines[1].starts_with("(top);ONCE "));
+        assert!(lines[2].starts_with("(top);TWICE "));
+        assert!(lines[3].starts_with("(top);TWICE;ONCE "));
+
+        let report = profiler.report(&debug, &p.memory, p.isa);
+        let flat = report.lines().skip_while(|l| !l.starts_with("flat")).nth(2).unwrap();
+        assert!(flat.ends_with("  LOOP"), "{}", flat);
+        let tree = report.lines().skip_while(|l| !l.starts_with("call tree")).skip(2).collect::<Vec<&str>>();
+        assert!(tree[0].contains("100.00%") && tree[0].ends_with("  (top)"));
+        assert!(tree[1].ends_with(" 1    TWICE"), "{}", tree[1]);
+        assert!(tree[2].ends_with(" 2      ONCE"), "{}", tree[2]);
+        assert!(tree[3].ends_with(" 1    ONCE"), "{}", tree[3]);
+    }
+
+    #[test]
+    fn deeper_than_max_depth() {
+        // REC calls itself 255 times below A and B, which is one frame too many
+        let src = "
+        lda #0xf0
+        ssp
+        jsr A
+        hlt
+A:      jsr B
+        rts
+B:      lda #0
+        tax
+REC:    inc
+        txa
+        jez DONE
+        jsr REC
+DONE:   rts";
+        let (prog, _) = assemble_with_debug(src).unwrap();
+        let mut p = ProcessorBuilder::new().memory(&prog).output(io::sink()).input(io::empty()).build();
+        let mut profiler = Profiler::new();
+        while !p.halted {
+            profiler.step(&mut p).unwrap();
+        }
+        assert_eq!(profiler.stacks[&Vec::new()].instructions, 4);
+    }
+
+    #[test]
+    fn interrupted() {
+        // input is waiting, so the interrupt is taken when the pc is on the jsr, before it runs
+        let src = "
+        lda #0xf0
+        ssp
+        sei
+CALL:   jsr SUB
+        hlt
+SUB:    rts
+        org 0x0100
+HANDLER: get
+        rti
+        org 0xfffe
+        dat 0x01
+        dat 0x00";
+        let (prog, debug) = assemble_with_debug(src).unwrap();
+        let mut p = ProcessorBuilder::new().memory(&prog).output(io::sink()).input(io::Cursor::new(b"a".to_vec())).build();
+        let mut profiler = Profiler::new();
+        while !p.halted {
+            profiler.step(&mut p).unwrap();
+        }
+        assert_eq!(profiler.total().cycles, p.cycles);
+        let handler = debug.labels["HANDLER"] as u16;
+        assert_eq!(profiler.addresses[&handler].instructions, 1);
+        assert_eq!(profiler.addresses[&(debug.labels["CALL"] as u16)].instructions, 1);
+        let sub = debug.labels["SUB"] as u16;
+        assert_eq!(profiler.calls, HashMap::from([((None, sub), 1)]));
+        let mut stacks = profiler.stacks.keys().cloned().collect::<Vec<Vec<u16>>>();
+        stacks.sort();
+        assert_eq!(stacks, [vec![], vec![sub]]);
+    }
+}
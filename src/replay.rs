@@ -0,0 +1,194 @@
+use std::collections::VecDeque;
+use std::fs::{read, File};
+use std::io::{self, BufWriter, Write};
+
+use crate::processor::Processor;
+
+/// the start of an input recording, followed by a format version
+pub const MAGIC: &[u8; 4] = b"AVCI";
+const VERSION: u8 = 1;
+
+/// input that arrived in one poll
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub cycle: u64, // the cycle count when it was polled
+    pub bytes: Vec<u8>
+}
+
+/// makes a program's input reproducible. a recording is every byte the processor reads from its input, with
+/// the cycle it arrived at, and replaying one gives the program the same bytes at the same cycles
+pub enum InputLog {
+    Record(InputRecorder),
+    Replay(VecDeque<Chunk>) // what hasn't been delivered yet
+}
+
+pub struct InputRecorder {
+    out: BufWriter<Box<dyn Write>>,
+    error: Option<io::Error> // the first error writing the recording. recording stops after one
+}
+
+impl InputRecorder {
+    pub fn new(out: impl Write + 'static) -> io::Result<InputRecorder> {
+        let mut out = BufWriter::new(Box::new(out) as Box<dyn Write>);
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        Ok(InputRecorder { out, error: None })
+    }
+    pub fn create(path: &str) -> Result<InputRecorder, String> {
+        match File::create(path).and_then(InputRecorder::new) {
+            Ok(r) => Ok(r),
+            Err(e) => Err(format!("unable to write input recording {}: {}", path, e))
+        }
+    }
+    /// flushes the recording, returning the first error writing it
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e)
+        }
+        self.out.flush()
+    }
+
+    /// each chunk is the cycle as 8 bytes, the length as 2, then the bytes, all big endian
+    fn record(&mut self, cycle: u64, bytes: &[u8]) {
+        if self.error.is_some() {
+            return
+        }
+        let mut record = cycle.to_be_bytes().to_vec();
+        record.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+        record.extend_from_slice(bytes);
+        if let Err(e) = self.out.write_all(&record) {
+            self.error = Some(e)
+        }
+    }
+}
+
+/// reads a recording made by `InputRecorder`
+pub fn parse_recording(bytes: &[u8]) -> Result<VecDeque<Chunk>, String> {
+    if bytes.len() < 5 || &bytes[..4] != MAGIC {
+        return Err(String::from("not an input recording"))
+    }
+    if bytes[4] != VERSION {
+        return Err(format!("input recording is version {}, but only version {} is supported", bytes[4], VERSION))
+    }
+    let mut chunks = VecDeque::new();
+    let mut pos = 5;
+    while pos < bytes.len() {
+        if pos + 10 > bytes.len() {
+            return Err(String::from("input recording is cut short"))
+        }
+        let cycle = u64::from_be_bytes(bytes[pos..pos + 8].try_into().unwrap());
+        let len = u16::from_be_bytes([bytes[pos + 8], bytes[pos + 9]]) as usize;
+        pos += 10;
+        if pos + len > bytes.len() {
+            return Err(String::from("input recording is cut short"))
+        }
+        chunks.push_back(Chunk { cycle, bytes: bytes[pos..pos + len].to_vec() });
+        pos += len
+    }
+    Ok(chunks)
+}
+
+pub fn load_recording(path: &str) -> Result<VecDeque<Chunk>, String> {
+    match read(path) {
+        Ok(bytes) => parse_recording(&bytes).map_err(|e| format!("{}: {}", path, e)),
+        Err(_) => Err(format!("unable to read input recording {}", path))
+    }
+}
+
+impl Processor {
+    /// while replaying, the reader is never touched. a chunk is delivered by the first poll at or after
+    /// its cycle, which is the poll that read it if the program hasn't changed
+    pub(crate) fn replay_input(&mut self) -> bool {
+        let chunks = match &mut self.input_log {
+            Some(InputLog::Replay(c)) => c,
+            _ => return false
+        };
+        while let Some(c) = chunks.front() {
+            if c.cycle > self.cycles {
+                break
+            }
+            self.get_buffer.extend_from_slice(&c.bytes);
+            if let Some(h) = &mut self.history {
+                h.record_arrival(c.cycle, &c.bytes)
+            }
+            chunks.pop_front();
+        }
+        true
+    }
+    pub(crate) fn log_input(&mut self, bytes: &[u8]) {
+        if let Some(InputLog::Record(r)) = &mut self.input_log {
+            r.record(self.cycles, bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::SharedBuffer;
+    use crate::assembler::assemble_with_debug;
+    use crate::processor::ProcessorBuilder;
+
+    /// gives out one byte every few reads, like a person typing
+    struct Typist {
+        text: Vec<u8>,
+        reads: usize
+    }
+    impl io::Read for Typist {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            if !self.reads.is_multiple_of(3) || self.text.is_empty() {
+                return Ok(0)
+            }
+            buf[0] = self.text.remove(0);
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn record_and_replay() {
+        // counts polls in x until there's input, then echoes it
+        let src = "
+LOOP:   inc
+        gbf
+        jez LOOP
+        get
+        put
+        tab
+        lda #0x2e
+        xor
+        jez END
+        jmp LOOP
+END:    hlt";
+        let (prog, _) = assemble_with_debug(src).unwrap();
+        let run = |input: Box<dyn io::Read>, log: InputLog| {
+            let out = SharedBuffer::default();
+            let mut p = ProcessorBuilder::new().memory(&prog).output(out.clone()).input(input).build();
+            p.input_log = Some(log);
+            while !p.halted && p.cycles < 10_000 {
+                p.step().unwrap();
+            }
+            let output = out.contents();
+            (p, output)
+        };
+
+        let recording = SharedBuffer::default();
+        let typist = Typist { text: b"hi.".to_vec(), reads: 0 };
+        let (mut p, output) = run(Box::new(typist), InputLog::Record(InputRecorder::new(recording.clone()).unwrap()));
+        assert!(p.halted);
+        match p.input_log.take() {
+            Some(InputLog::Record(r)) => r.finish().unwrap(),
+            _ => unreachable!()
+        }
+        let chunks = parse_recording(&recording.contents()).unwrap();
+        assert_eq!(chunks.iter().map(|c| c.bytes.clone()).collect::<Vec<Vec<u8>>>(), vec![b"h".to_vec(), b"i".to_vec(), b".".to_vec()]);
+
+        // the input replayed is the same, and arrives at the same point, even though there's other input waiting
+        let (q, replayed) = run(Box::new(io::Cursor::new(b"junk".to_vec())), InputLog::Replay(chunks));
+        assert_eq!(replayed, output);
+        assert_eq!((q.x, q.cycles), (p.x, p.cycles));
+
+        assert!(parse_recording(b"AVCI\x01\0\0").is_err());
+        assert!(parse_recording(b"AVCT\x01").is_err());
+    }
+}
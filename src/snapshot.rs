@@ -0,0 +1,248 @@
+use std::fs::{read, write};
+
+use crate::bus::DeviceState;
+use crate::isa::Isa;
+use crate::processor::{Fault, Processor};
+use crate::utils::ByteReader;
+
+/// the start of a saved state file, followed by a format version
+pub const MAGIC: &[u8; 4] = b"AVCS";
+pub const VERSION: u8 = 2;
+
+/// everything about a machine that changes as it runs. settings like the fault policy aren't included,
+/// and neither is anything outside the machine, like output that's already been written
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub isa: Isa,
+    pub memory: Vec<u8>, // all 65536 bytes
+    pub a: u8,
+    pub b: u8,
+    pub x: u8,
+    pub status: u8,
+    pub program_counter: u16,
+    pub stack_pointer: u16,
+    pub halted: bool,
+    pub cycles: u64,
+    pub get_buffer: Vec<u8>,
+    pub fault: Option<Fault>,
+    pub fault_pc: u16,
+    pub devices: Vec<DeviceState>
+}
+
+impl Processor {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            isa: self.isa,
+            memory: self.memory.to_vec(),
+            a: self.a,
+            b: self.b,
+            x: self.x,
+            status: self.status,
+            program_counter: self.program_counter,
+            stack_pointer: self.stack_pointer,
+            halted: self.halted,
+            cycles: self.cycles,
+            get_buffer: self.get_buffer.clone(),
+            fault: self.fault.clone(),
+            fault_pc: self.fault_pc,
+            devices: self.bus.save_state()
+        }
+    }
+    /// the bus has to have the same devices mapped as when the snapshot was taken
+    pub fn restore(&mut self, s: &Snapshot) -> Result<(), String> {
+        if s.memory.len() != self.memory.len() {
+            return Err(String::from("saved state has the wrong amount of memory"))
+        }
+        self.bus.load_state(&s.devices)?;
+        self.isa = s.isa;
+        self.memory.copy_from_slice(&s.memory);
+        self.a = s.a;
+        self.b = s.b;
+        self.x = s.x;
+        self.status = s.status;
+        self.program_counter = s.program_counter;
+        self.stack_pointer = s.stack_pointer;
+        self.halted = s.halted;
+        self.cycles = s.cycles;
+        self.get_buffer = s.get_buffer.clone();
+        self.fault = s.fault.clone();
+        self.fault_pc = s.fault_pc;
+        // stepping back from here would undo steps that never happened in the restored state
+        if let Some(h) = &mut self.history {
+            h.clear()
+        }
+        Ok(())
+    }
+}
+
+impl Snapshot {
+    /// the file format is the fields in order, big endian, after the magic and version.
+    /// variable length things are prefixed with their length
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut ret = MAGIC.to_vec();
+        ret.push(VERSION);
+        ret.push(self.isa.revision());
+        ret.extend_from_slice(&self.memory);
+        ret.extend_from_slice(&[self.a, self.b, self.x, self.status]);
+        ret.extend_from_slice(&self.program_counter.to_be_bytes());
+        ret.extend_from_slice(&self.stack_pointer.to_be_bytes());
+        ret.push(self.halted as u8);
+        ret.extend_from_slice(&self.cycles.to_be_bytes());
+        put_bytes(&mut ret, &self.get_buffer);
+        // faults are their code, then whatever they carry
+        match &self.fault {
+            None => ret.push(0),
+            Some(f) => {
+                ret.push(f.code());
+                match f {
+                    Fault::IllegalOpcode(op) => ret.push(*op),
+                    Fault::Io(e) => put_bytes(&mut ret, e.as_bytes()),
+                    Fault::Unmapped(addr) => ret.extend_from_slice(&addr.to_be_bytes()),
+                    _ => {}
+                }
+            }
+        }
+        ret.extend_from_slice(&self.fault_pc.to_be_bytes());
+        ret.extend_from_slice(&(self.devices.len() as u16).to_be_bytes());
+        for d in &self.devices {
+            ret.extend_from_slice(&d.start.to_be_bytes());
+            ret.extend_from_slice(&d.end.to_be_bytes());
+            put_bytes(&mut ret, d.name.as_bytes());
+            put_bytes(&mut ret, &d.state)
+        }
+        ret
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, String> {
+        let mut r = ByteReader::new(bytes, "saved state");
+        if r.take(4)? != MAGIC {
+            return Err(String::from("not a saved state"))
+        }
+        let version = r.u8()?;
+        if version == 0 || version > VERSION {
+            return Err(format!("saved state is version {}, but only versions up to {} are supported", version, VERSION))
+        }
+        let isa = match version { // version 1 was before there was more than one isa
+            1 => Isa::V1,
+            _ => {
+                let revision = r.u8()?;
+                match Isa::from_revision(revision) {
+                    Some(isa) => isa,
+                    None => return Err(format!("saved state is for unknown isa revision {}", revision))
+                }
+            }
+        };
+        let memory = r.take(65536)?.to_vec();
+        let regs = r.take(4)?;
+        let (a, b, x, status) = (regs[0], regs[1], regs[2], regs[3]);
+        let program_counter = r.u16()?;
+        let stack_pointer = r.u16()?;
+        let halted = r.u8()? != 0;
+        let cycles = u64::from_be_bytes(r.take(8)?.try_into().unwrap());
+        let get_buffer = r.bytes()?.to_vec();
+        let fault = match r.u8()? {
+            0 => None,
+            1 => Some(Fault::StackOverflow),
+            2 => Some(Fault::StackUnderflow),
+            3 => Some(Fault::IllegalOpcode(r.u8()?)),
+            4 => Some(Fault::Io(String::from_utf8_lossy(r.bytes()?).into_owned())),
+            5 => Some(Fault::CycleBudgetExceeded),
+            6 => Some(Fault::Unmapped(r.u16()?)),
+            c => return Err(format!("unknown fault code {} in saved state", c))
+        };
+        let fault_pc = r.u16()?;
+        let mut devices = Vec::new();
+        for _ in 0..r.u16()? {
+            let start = r.u16()?;
+            let end = r.u16()?;
+            let name = String::from_utf8_lossy(r.bytes()?).into_owned();
+            let state = r.bytes()?.to_vec();
+            devices.push(DeviceState { start, end, name, state })
+        }
+        r.finish()?;
+        Ok(Snapshot { isa, memory, a, b, x, status, program_counter, stack_pointer, halted, cycles, get_buffer, fault, fault_pc, devices })
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        match write(path, self.to_bytes()) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("unable to write saved state {}: {}", path, e))
+        }
+    }
+    pub fn load(path: &str) -> Result<Snapshot, String> {
+        match read(path) {
+            Ok(bytes) => Snapshot::from_bytes(&bytes).map_err(|e| format!("{}: {}", path, e)),
+            Err(_) => Err(format!("unable to read saved state {}", path))
+        }
+    }
+}
+
+fn put_bytes(v: &mut Vec<u8>, bytes: &[u8]) {
+    v.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    v.extend_from_slice(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Cursor};
+    use crate::assembler::assemble_with_debug;
+    use crate::bus::Bus;
+    use crate::processor::ProcessorBuilder;
+
+    #[test]
+    fn save_and_restore() {
+        // reads a byte from the rng into ram, counts with x, and echoes input
+        let src = "
+LOOP:   lda 0xff00
+        sta 0x0300
+        inc
+        get
+        put
+        jmp LOOP";
+        let (prog, _) = assemble_with_debug(src).unwrap();
+        let build = |input: &[u8]| ProcessorBuilder::new()
+            .memory(&prog)
+            .output(io::sink())
+            .input(Cursor::new(input.to_vec()))
+            .bus(Bus::from_config("0xff00 0xff00 rng 7\n0xfff0 0xfff4 timer").unwrap())
+            .build();
+        let mut p = build(b"abcdef");
+        p.memory[0xfff0] = 0;
+        for _ in 0..14 {
+            p.step().unwrap();
+        }
+        let snapshot = p.snapshot();
+        let bytes = snapshot.to_bytes();
+        assert_eq!(&bytes[..6], b"AVCS\x02\x01");
+        let loaded = Snapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded, snapshot);
+
+        // carrying on from the restored state goes the same way as carrying on from the original.
+        // the input stream isn't part of the machine, and the original has already read all of it
+        let mut q = build(b"");
+        q.record_history(100);
+        q.step().unwrap();
+        q.restore(&loaded).unwrap();
+        assert!(q.step_back().is_none());
+        for _ in 0..12 {
+            p.step().unwrap();
+            q.step().unwrap();
+        }
+        assert_eq!(p.snapshot(), q.snapshot());
+        assert_eq!(q.x, 4);
+
+        let mut no_devices = ProcessorBuilder::new().output(io::sink()).input(io::empty()).build();
+        assert!(no_devices.restore(&loaded).is_err());
+        let mut faulted = snapshot.clone();
+        faulted.fault = Some(Fault::Io(String::from("gone")));
+        assert_eq!(Snapshot::from_bytes(&faulted.to_bytes()), Ok(faulted));
+        assert!(Snapshot::from_bytes(&bytes[..100]).is_err());
+        assert!(Snapshot::from_bytes(b"AVCS\x03").unwrap_err().contains("version 3"));
+        // version 1 had no isa
+        let mut old = bytes.clone();
+        old.remove(5);
+        old[4] = 1;
+        assert_eq!(Snapshot::from_bytes(&old), Ok(snapshot.clone()));
+    }
+}
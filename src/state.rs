@@ -0,0 +1,175 @@
+use std::fmt;
+
+use crate::processor::{Processor, CARRY, INTERRUPT_ENABLE, NEGATIVE, OVERFLOW, ZERO};
+
+/// the status register, decoded
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Flags {
+    pub negative: bool,
+    pub overflow: bool,
+    pub interrupt_enable: bool,
+    pub zero: bool,
+    pub carry: bool
+}
+
+impl Flags {
+    pub fn from_status(s: u8) -> Flags {
+        Flags {
+            negative: s & NEGATIVE != 0,
+            overflow: s & OVERFLOW != 0,
+            interrupt_enable: s & INTERRUPT_ENABLE != 0,
+            zero: s & ZERO != 0,
+            carry: s & CARRY != 0
+        }
+    }
+    pub fn to_status(&self) -> u8 {
+        let mut s = 0;
+        for (set, flag) in [(self.negative, NEGATIVE), (self.overflow, OVERFLOW), (self.interrupt_enable, INTERRUPT_ENABLE),
+            (self.zero, ZERO), (self.carry, CARRY)] {
+            if set {
+                s |= flag
+            }
+        }
+        s
+    }
+}
+
+/// the letters of the flags that are set, in the same places as the readout, like `n----iz-`
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let letter = |set: bool, c: char| if set { c } else { '-' };
+        write!(f, "{}{}---{}{}{}", letter(self.negative, 'n'), letter(self.overflow, 'v'),
+            letter(self.interrupt_enable, 'i'), letter(self.zero, 'z'), letter(self.carry, 'c'))
+    }
+}
+
+/// the registers and whether the processor has stopped, for tools to look at without parsing the readout
+#[derive(Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuState {
+    pub a: u8,
+    pub b: u8,
+    pub x: u8,
+    pub pc: u16,
+    pub sp: u16,
+    pub status: u8,
+    pub flags: Flags,
+    pub cycles: u64,
+    pub halted: bool,
+    pub fault: Option<String> // the fault that halted the processor, if one did
+}
+
+impl Processor {
+    pub fn state(&self) -> CpuState {
+        CpuState {
+            a: self.a,
+            b: self.b,
+            x: self.x,
+            pc: self.program_counter,
+            sp: self.stack_pointer,
+            status: self.status,
+            flags: Flags::from_status(self.status),
+            cycles: self.cycles,
+            halted: self.halted,
+            fault: self.fault.as_ref().map(|f| f.to_string())
+        }
+    }
+}
+
+impl CpuState {
+    /// one line, as `--dump-state json` prints it. this is the only json format for the state; the serde
+    /// derives are for putting a `CpuState` into formats of the caller's choosing
+    pub fn to_json(&self) -> String {
+        let f = &self.flags;
+        let fault = match &self.fault {
+            Some(s) => json_string(s),
+            None => String::from("null")
+        };
+        format!(
+            "{{\"a\":{},\"b\":{},\"x\":{},\"pc\":{},\"sp\":{},\"status\":{},\"flags\":{{\"negative\":{},\"overflow\":{},\"interrupt_enable\":{},\"zero\":{},\"carry\":{}}},\"cycles\":{},\"halted\":{},\"fault\":{}}}",
+            self.a, self.b, self.x, self.pc, self.sp, self.status, f.negative, f.overflow, f.interrupt_enable, f.zero, f.carry,
+            self.cycles, self.halted, fault
+        )
+    }
+}
+
+/// the register readout that `-d` prints
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "a :   0x{:0>2x}", self.a)?;
+        writeln!(f, "b :   0x{:0>2x}", self.b)?;
+        writeln!(f, "x :   0x{:0>2x}", self.x)?;
+        writeln!(f, "pc: 0x{:0>4x}", self.pc)?;
+        writeln!(f, "sp: 0x{:0>4x}", self.sp)?;
+        writeln!(f, "      nv---izc")?;
+        writeln!(f, "s : 0b{:0>8b}", self.status)?;
+        write!(f, "cycles: {}", self.cycles)?;
+        if let Some(fault) = &self.fault {
+            write!(f, "\nfault: {}", fault)?
+        }
+        Ok(())
+    }
+}
+
+/// how `--dump-state` prints the state
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StateFormat {
+    Text, // the readout
+    Json // one line
+}
+impl StateFormat {
+    pub fn from_name(s: &str) -> Result<StateFormat, String> {
+        match s {
+            "text" => Ok(StateFormat::Text),
+            "json" => Ok(StateFormat::Json),
+            _ => Err(format!("unknown state format {}, expected text or json", s))
+        }
+    }
+}
+
+/// a json string literal, with quotes, backslashes and control characters escaped
+fn json_string(s: &str) -> String {
+    let mut ret = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            '\r' => ret.push_str("\\r"),
+            '\t' => ret.push_str("\\t"),
+            '\u{8}' => ret.push_str("\\b"),
+            '\u{c}' => ret.push_str("\\f"),
+            c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:0>4x}", c as u32)),
+            c => ret.push(c)
+        }
+    }
+    ret.push('"');
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::Fault;
+
+    #[test]
+    fn state() {
+        let mut p = Processor::new_with_memory(&[]);
+        p.a = 0x2a;
+        p.program_counter = 0x0300;
+        p.status = NEGATIVE | ZERO;
+        p.halted = true;
+        p.fault = Some(Fault::Io(String::from("said \"no\"\n")));
+        let s = p.state();
+        assert_eq!(s.flags, Flags { negative: true, zero: true, ..Flags::default() });
+        assert_eq!(s.flags.to_status(), s.status);
+        assert_eq!(s.flags.to_string(), "n-----z-");
+        assert_eq!(s.to_json(), concat!(
+            "{\"a\":42,\"b\":0,\"x\":0,\"pc\":768,\"sp\":0,\"status\":130,",
+            "\"flags\":{\"negative\":true,\"overflow\":false,\"interrupt_enable\":false,\"zero\":true,\"carry\":false},",
+            "\"cycles\":0,\"halted\":true,\"fault\":\"i/o error: said \\\"no\\\"\\n\"}"
+        ));
+        assert!(s.to_string().ends_with("cycles: 0\nfault: i/o error: said \"no\"\n"));
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::fs::{read_to_string, write};
+use std::io;
+
+use crate::assembler::{assemble_with_isa, Annotation, AnnotationKind, DebugInfo};
+use crate::bus::Bus;
+use crate::condition::Condition;
+use crate::coverage::Coverage;
+use crate::isa::Isa;
+use crate::processor::{Processor, ProcessorBuilder};
+use crate::utils::{Options, SharedBuffer};
+
+/// tests that haven't halted after this many instructions are assumed to be stuck
+const TEST_STEP_LIMIT: usize = 1_000_000;
+
+struct Test<'a> {
+    name: String,
+    entry: usize,
+    expected_out: Vec<u8>,
+    asserts: Vec<&'a Annotation>
+}
+
+/// assembles the source file and runs every test declared in it
+pub fn run_tests(po: &Options) -> Result<(), String> {
+    let program = match read_to_string(&po.path) {
+        Ok(s) => s,
+        Err(_) => return Err(format!("unable to read file {}", po.path))
+    };
+    let (prog, debug) = assemble_with_isa(&program, Isa::from_options(po)?.unwrap_or_default())?;
+    let mut coverage = if po.coverage.is_empty() { None } else { Some(Coverage::new()) };
+    let results = run_all(&prog, &debug, po, &mut coverage);
+    let mut failed = 0;
+    for (name, result) in &results {
+        match result {
+            Ok(_) => println!("test {} ... ok", name),
+            Err(e) => {
+                println!("test {} ... FAILED\n    {}", name, e);
+                failed += 1
+            }
+        }
+    }
+    println!("\n{} passed; {} failed", results.len() - failed, failed);
+    if let Some(c) = &coverage {
+        if let Err(e) = write(&po.coverage, c.lcov(&debug, &prog, &po.path)) {
+            return Err(format!("unable to write coverage to {}: {}", po.coverage, e))
+        }
+    }
+
+    if failed == 0 {
+        Ok(())
+    }
+    else {
+        Err(format!("{} test(s) failed", failed))
+    }
+}
+
+/// runs every test declared in an assembled program, returning each one's name and result
+fn run_all(prog: &[u8], debug: &DebugInfo, po: &Options, coverage: &mut Option<Coverage>) -> Vec<(String, Result<(), String>)> {
+    // annotations before the first test directive belong to the program itself.
+    // its asserts are checked in every test, since they usually sit in shared routines
+    let mut main = Test { name: String::from("main"), entry: debug.entry.unwrap_or(0) as usize, expected_out: Vec::new(), asserts: Vec::new() };
+    let mut tests = Vec::new();
+    for a in &debug.annotations {
+        match &a.kind {
+            AnnotationKind::Test(name) => tests.push(Test {
+                name: name.clone(),
+                entry: a.address,
+                expected_out: Vec::new(),
+                asserts: Vec::new()
+            }),
+            AnnotationKind::ExpectOut(bytes) => tests.last_mut().unwrap_or(&mut main).expected_out.extend_from_slice(bytes),
+            AnnotationKind::Assert(_) => tests.last_mut().unwrap_or(&mut main).asserts.push(a)
+        }
+    }
+    let mut global_asserts: HashMap<usize, Vec<&Annotation>> = HashMap::new();
+    for a in &main.asserts {
+        global_asserts.entry(a.address).or_default().push(*a)
+    }
+    let mut runs = Vec::new();
+    if tests.is_empty() || !main.expected_out.is_empty() {
+        runs.push((main, HashMap::new())) // main's asserts are its own, not global
+    }
+    for t in tests {
+        runs.push((t, global_asserts.clone()))
+    }
+
+    let mut results = Vec::new();
+    for (t, global_asserts) in &runs {
+        results.push((t.name.clone(), run_test(prog, debug, t, global_asserts, po, coverage)))
+    }
+    results
+}
+
+fn condition(a: &Annotation) -> &Condition {
+    match &a.kind {
+        AnnotationKind::Assert(c) => c,
+        _ => unreachable!()
+    }
+}
+
+fn check(a: &Annotation, p: &Processor) -> Result<(), String> {
+    let c = condition(a);
+    if c.eval(p) {
+        Ok(())
+    }
+    else {
+        Err(format!("line {}: assert {} failed ({})", a.line, c, c.explain(p)))
+    }
+}
+
+/// adds the test's coverage to `coverage`, whether it passes or not
+fn run_test(prog: &[u8], debug: &DebugInfo, t: &Test, global_asserts: &HashMap<usize, Vec<&Annotation>>, po: &Options,
+    coverage: &mut Option<Coverage>) -> Result<(), String> {
+    let output = SharedBuffer::default();
+    let mut p = ProcessorBuilder::new()
+        .memory(prog)
+        .bus(Bus::from_options(po)?)
+        .isa(Isa::from_options(po)?.unwrap_or_default())
+        .output(output.clone())
+        .input(io::empty())
+        .build();
+    p.fault_policy = po.fault_policy;
+    p.program_counter = t.entry as u16;
+    p.stack_pointer = debug.stack_pointer.unwrap_or(0);
+    p.coverage = coverage.take();
+    let result = check_run(&mut p, debug, t, global_asserts, po, &output);
+    *coverage = p.coverage.take();
+    result
+}
+
+fn check_run(p: &mut Processor, debug: &DebugInfo, t: &Test, global_asserts: &HashMap<usize, Vec<&Annotation>>, po: &Options,
+    output: &SharedBuffer) -> Result<(), String> {
+    let mut checked = vec![false; t.asserts.len()];
+    let mut steps = 0;
+    while !p.halted {
+        if let Some(asserts) = global_asserts.get(&(p.program_counter as usize)) {
+            for a in asserts {
+                check(a, p)?
+            }
+        }
+        for (i, a) in t.asserts.iter().enumerate() {
+            if a.address == p.program_counter as usize {
+                check(a, p)?;
+                checked[i] = true
+            }
+        }
+        if steps == TEST_STEP_LIMIT {
+            let line = match debug.line_at(p.program_counter as usize) {
+                Some(l) => format!(" (line {})", l),
+                None => String::new()
+            };
+            return Err(format!("did not halt within {} instructions, pc 0x{:0>4x}{}", TEST_STEP_LIMIT, p.program_counter, line))
+        }
+        p.execute(false, po);
+        steps += 1
+    }
+
+    if let Some(f) = &p.fault {
+        let line = match debug.line_at(p.fault_pc as usize) {
+            Some(l) => format!("line {}: ", l),
+            None => String::new()
+        };
+        return Err(format!("{}fault at 0x{:0>4x}: {}", line, p.fault_pc, f))
+    }
+
+    // asserts after the last instruction of a test are about the final state
+    for (i, a) in t.asserts.iter().enumerate() {
+        if !checked[i] {
+            check(a, p)?
+        }
+    }
+    let out = output.contents();
+    if !t.expected_out.is_empty() && out != t.expected_out {
+        return Err(format!("expected output {:?}, got {:?}", String::from_utf8_lossy(&t.expected_out), String::from_utf8_lossy(&out)))
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results(src: &str) -> Vec<(String, Result<(), String>)> {
+        let (prog, debug) = assemble_with_isa(src, Isa::V1).unwrap();
+        run_all(&prog, &debug, &Options::default(), &mut None)
+    }
+
+    #[test]
+    fn tests() {
+        let r = results("
+        jmp END
+PRINT:  lda #0x68
+        put
+        assert a == 0x68
+        rts
+        test \"passes\"
+        lda #0x10
+        ssp
+        jsr PRINT
+        hlt
+        expect_out \"h\"
+        assert x == 0
+        test \"wrong output\"
+        lda #0x10
+        ssp
+        jsr PRINT
+        jsr PRINT
+        hlt
+        expect_out \"h\"
+        test \"assert fails\"
+        lda #3
+        tax
+        assert x == 4
+        hlt
+        test \"never halts\"
+LOOP:   jmp LOOP
+END:    hlt");
+        let names = r.iter().map(|(name, _)| name.as_str()).collect::<Vec<&str>>();
+        assert_eq!(names, ["passes", "wrong output", "assert fails", "never halts"]);
+        assert_eq!(r[0].1, Ok(()));
+        assert_eq!(r[1].1, Err(String::from("expected output \"h\", got \"hh\"")));
+        assert_eq!(r[2].1, Err(String::from("line 24: assert x == 4 failed (x is 0x3)")));
+        assert_eq!(r[3].1, Err(format!("did not halt within {} instructions, pc 0x001c (line 27)", TEST_STEP_LIMIT)));
+    }
+
+    #[test]
+    fn annotation_errors() {
+        let error = |src: &str| assemble_with_isa(src, Isa::V1).map(|_| ()).unwrap_err();
+        assert!(error("hlt\nassert a == NOWHERE").contains("NOWHERE"));
+        assert!(error("hlt\nexpect_out NOWHERE").contains("NOWHERE"));
+        assert!(error("hlt\nexpect_out").contains("expect_out requires op"));
+        assert!(error("test 5\nhlt").contains("test name must be a string"));
+    }
+}
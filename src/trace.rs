@@ -0,0 +1,260 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::disassembler::disassemble;
+use crate::isa::Isa;
+use crate::processor::Processor;
+use crate::utils::parse_int_literal;
+
+/// the start of a binary trace, followed by a format version
+pub const BINARY_MAGIC: &[u8; 4] = b"AVCT";
+const BINARY_VERSION: u8 = 1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TraceFormat {
+    Json, // one object per line
+    Binary
+}
+impl TraceFormat {
+    pub fn from_name(s: &str) -> Result<TraceFormat, String> {
+        match s {
+            "json" => Ok(TraceFormat::Json),
+            "binary" => Ok(TraceFormat::Binary),
+            _ => Err(format!("unknown trace format {}", s))
+        }
+    }
+}
+
+/// writes a record of every instruction the processor executes, or the ones in some address ranges
+pub struct Tracer {
+    out: BufWriter<Box<dyn Write>>,
+    format: TraceFormat,
+    ranges: Vec<(u16, u16)>, // inclusive, and empty for everything
+    record: Record,
+    error: Option<io::Error> // the first error writing the trace. tracing stops after one
+}
+
+#[derive(Default)]
+struct Record {
+    fetched: bool, // false if the step stopped before fetching, e.g. out of cycles
+    cycle: u64,
+    pc: u16,
+    opcode: u8,
+    operands: Vec<u8>,
+    disassembly: String,
+    effective_address: Option<u16>,
+    before: Registers,
+    after: Registers,
+    writes: Vec<(u16, u8)>
+}
+
+#[derive(Default, Clone, Copy)]
+struct Registers {
+    a: u8,
+    b: u8,
+    x: u8,
+    s: u8,
+    sp: u16,
+    pc: u16
+}
+impl Registers {
+    fn of(p: &Processor) -> Registers {
+        Registers { a: p.a, b: p.b, x: p.x, s: p.status, sp: p.stack_pointer, pc: p.program_counter }
+    }
+}
+
+impl Tracer {
+    pub fn new(out: impl Write + 'static, format: TraceFormat, ranges: Vec<(u16, u16)>) -> io::Result<Tracer> {
+        let mut out = BufWriter::new(Box::new(out) as Box<dyn Write>);
+        if format == TraceFormat::Binary {
+            out.write_all(BINARY_MAGIC)?;
+            out.write_all(&[BINARY_VERSION])?
+        }
+        Ok(Tracer { out, format, ranges, record: Record::default(), error: None })
+    }
+    pub fn create(path: &str, format: TraceFormat, ranges: Vec<(u16, u16)>) -> Result<Tracer, String> {
+        match File::create(path).and_then(|f| Tracer::new(f, format, ranges)) {
+            Ok(t) => Ok(t),
+            Err(e) => Err(format!("unable to write trace file {}: {}", path, e))
+        }
+    }
+    /// flushes the trace, returning the first error writing it
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e)
+        }
+        self.out.flush()
+    }
+
+    pub(crate) fn begin(&mut self, p: &Processor) {
+        self.record = Record {
+            cycle: p.cycles,
+            before: Registers::of(p),
+            ..Record::default()
+        }
+    }
+    /// called once any interrupt has been taken, so this is the instruction that actually runs
+    pub(crate) fn fetch(&mut self, pc: u16, memory: &[u8], isa: Isa) {
+        let (disassembly, len) = disassemble(memory, pc as usize, isa, None);
+        self.record.fetched = true;
+        self.record.pc = pc;
+        self.record.opcode = memory[pc as usize];
+        self.record.operands = (1..len).map(|i| memory[pc.wrapping_add(i as u16) as usize]).collect();
+        self.record.disassembly = disassembly
+    }
+    pub(crate) fn effective_address(&mut self, addr: u16) {
+        self.record.effective_address = Some(addr)
+    }
+    pub(crate) fn write(&mut self, addr: u16, val: u8) {
+        self.record.writes.push((addr, val))
+    }
+    pub(crate) fn end(&mut self, p: &Processor) {
+        if self.error.is_some() || !self.record.fetched {
+            return
+        }
+        let pc = self.record.pc;
+        if !self.ranges.is_empty() && !self.ranges.iter().any(|(start, end)| pc >= *start && pc <= *end) {
+            return
+        }
+        self.record.after = Registers::of(p);
+        let result = match self.format {
+            TraceFormat::Json => self.write_json(),
+            TraceFormat::Binary => self.write_binary()
+        };
+        if let Err(e) = result {
+            self.error = Some(e)
+        }
+    }
+
+    fn write_json(&mut self) -> io::Result<()> {
+        let r = &self.record;
+        let registers = |r: &Registers| format!(
+            "{{\"a\":{},\"b\":{},\"x\":{},\"s\":{},\"sp\":{},\"pc\":{}}}", r.a, r.b, r.x, r.s, r.sp, r.pc
+        );
+        let operands = r.operands.iter().map(|o| o.to_string()).collect::<Vec<String>>().join(",");
+        let writes = r.writes.iter().map(|(a, v)| format!("[{},{}]", a, v)).collect::<Vec<String>>().join(",");
+        let ea = match r.effective_address {
+            Some(a) => a.to_string(),
+            None => String::from("null")
+        };
+        // disassembly without labels is only ever letters, digits, spaces and #(),x, so it doesn't need escaping
+        writeln!(self.out,
+            "{{\"cycle\":{},\"pc\":{},\"opcode\":{},\"operands\":[{}],\"disassembly\":\"{}\",\"effective_address\":{},\"before\":{},\"after\":{},\"writes\":[{}]}}",
+            r.cycle, r.pc, r.opcode, operands, r.disassembly, ea, registers(&r.before), registers(&r.after), writes
+        )
+    }
+    /// see readme.md for the layout. everything is big endian, like the machine
+    fn write_binary(&mut self) -> io::Result<()> {
+        let r = &self.record;
+        let mut bytes = Vec::with_capacity(32);
+        bytes.extend_from_slice(&r.cycle.to_be_bytes());
+        bytes.extend_from_slice(&r.pc.to_be_bytes());
+        bytes.push(r.opcode);
+        bytes.push(r.operands.len() as u8);
+        bytes.extend_from_slice(&r.operands);
+        match r.effective_address {
+            Some(a) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&a.to_be_bytes())
+            }
+            None => bytes.push(0)
+        }
+        for regs in [&r.before, &r.after] {
+            bytes.extend_from_slice(&[regs.a, regs.b, regs.x, regs.s]);
+            bytes.extend_from_slice(&regs.sp.to_be_bytes());
+            bytes.extend_from_slice(&regs.pc.to_be_bytes())
+        }
+        bytes.push(r.writes.len() as u8);
+        for (a, v) in r.writes.iter() {
+            bytes.extend_from_slice(&a.to_be_bytes());
+            bytes.push(*v)
+        }
+        self.out.write_all(&bytes)
+    }
+}
+
+/// ranges look like `START:END`, separated by commas. both ends are included
+pub fn parse_ranges(s: &str) -> Result<Vec<(u16, u16)>, String> {
+    let mut ranges = Vec::new();
+    for range in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        let (start, end) = match range.split_once(':') {
+            Some(r) => r,
+            None => return Err(format!("address range {} should look like START:END", range))
+        };
+        let start = parse_int_literal::<u16>(start.trim())?;
+        let end = parse_int_literal::<u16>(end.trim())?;
+        if end < start {
+            return Err(format!("address range {} ends before it starts", range))
+        }
+        ranges.push((start, end))
+    }
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::SharedBuffer;
+    use crate::assembler::assemble_with_debug;
+    use crate::processor::ProcessorBuilder;
+
+    fn trace(format: TraceFormat, ranges: Vec<(u16, u16)>) -> Vec<u8> {
+        let (prog, _) = assemble_with_debug("lda #0x2a\nsta 0x0300,x\nhlt").unwrap();
+        let mut p = ProcessorBuilder::new().memory(&prog).output(io::sink()).input(io::empty()).build();
+        let out = SharedBuffer::default();
+        p.tracer = Some(Tracer::new(out.clone(), format, ranges).unwrap());
+        p.x = 1;
+        while !p.halted {
+            p.step().unwrap();
+        }
+        p.tracer.take().unwrap().finish().unwrap();
+        out.contents()
+    }
+
+    #[test]
+    fn json_lines() {
+        let out = String::from_utf8(trace(TraceFormat::Json, Vec::new())).unwrap();
+        let lines = out.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], "{\"cycle\":2,\"pc\":2,\"opcode\":137,\"operands\":[3,0],\"disassembly\":\"sta 0x0300,x\",\
+            \"effective_address\":769,\"before\":{\"a\":42,\"b\":0,\"x\":1,\"s\":0,\"sp\":0,\"pc\":2},\
+            \"after\":{\"a\":42,\"b\":0,\"x\":1,\"s\":0,\"sp\":0,\"pc\":5},\"writes\":[[769,42]]}");
+        assert!(lines[2].contains("\"disassembly\":\"hlt\""));
+
+        let out = String::from_utf8(trace(TraceFormat::Json, parse_ranges("0:1, 5:0x10").unwrap())).unwrap();
+        assert_eq!(out.lines().count(), 2);
+    }
+
+    #[test]
+    fn out_of_cycles() {
+        let (prog, _) = assemble_with_debug("lda #0x2a\nsta 0x0300\nhlt").unwrap();
+        let mut p = ProcessorBuilder::new().memory(&prog).output(io::sink()).input(io::empty()).build();
+        let out = SharedBuffer::default();
+        p.tracer = Some(Tracer::new(out.clone(), TraceFormat::Json, Vec::new()).unwrap());
+        p.cycle_budget = Some(2);
+        p.step().unwrap();
+        assert!(p.step().is_err());
+        p.tracer.take().unwrap().finish().unwrap();
+        let out = String::from_utf8(out.contents()).unwrap();
+        assert_eq!(out.lines().count(), 1); // nothing for the step that ran out
+        assert!(out.contains("\"disassembly\":\"lda #0x2a\""));
+    }
+
+    #[test]
+    fn binary() {
+        let out = trace(TraceFormat::Binary, vec![(2, 2)]);
+        assert_eq!(&out[..5], b"AVCT\x01");
+        let record = &out[5..];
+        assert_eq!(&record[..8], &2u64.to_be_bytes());
+        assert_eq!(&record[8..16], &[0, 2, 137, 2, 3, 0, 1, 0x03]); // pc, opcode, operands, effective address
+        assert_eq!(record.len(), 8 + 2 + 1 + 3 + 3 + 8 + 8 + 1 + 3);
+        assert_eq!(&record[record.len() - 4..], &[1, 0x03, 0x01, 42]);
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_ranges("0x10:0x20,5:5"), Ok(vec![(0x10, 0x20), (5, 5)]));
+        assert!(parse_ranges("0x20:0x10").is_err());
+        assert!(parse_ranges("0x20").is_err());
+    }
+}
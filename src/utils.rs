@@ -1,11 +1,21 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
 use num_traits::Num;
 
+use crate::processor::FaultPolicy;
+
 #[derive(Clone)]
 pub enum Command {
     Assemble,
     Run,
     AssAndRun,
+    Test,
+    Debug,
+    Profile,
 }
+#[allow(clippy::derivable_impls)]
 impl Default for Command { fn default() -> Self { Self::Run } }
 #[derive(Default)]
 pub struct Options {
@@ -14,6 +24,46 @@ pub struct Options {
     pub out_path: String,
     pub debug_level: i32,
     pub clock_period: u64,
+    pub clock_speed: u64, // target hz, 0 is unlimited
+    pub memory_map: String,
+    pub timer: bool,
+    pub fault_policy: FaultPolicy,
+    pub max_cycles: u64, // 0 is unlimited
+    pub gdb: String, // address to serve the gdb remote protocol on, empty for none
+    pub trace: String, // trace file path, empty for none
+    pub trace_format: String,
+    pub trace_range: String,
+    pub folded: String, // folded stacks output path for -P, empty for none
+    pub coverage: String, // lcov output path, empty for none
+    pub save_state: String, // where to save the machine when the run ends, empty for nowhere
+    pub load_state: String, // a saved machine to start from, empty for none
+    pub isa: String, // --isa, empty if it wasn't given
+    pub record_input: String, // where to record input, empty for nowhere
+    pub replay_input: String, // a recording to replay instead of reading input, empty for none
+    pub executable: bool, // assemble to an executable instead of a raw image
+    pub strip: bool, // leave the symbol table out of executables
+    pub dump_mem: String, // address ranges to hexdump on brk and when the run ends, empty for none
+    pub dump_mem_file: String, // where to write the raw bytes of those ranges when the run ends, empty for nowhere
+    pub dump_state: String, // how to print the state when the run ends, empty for not at all
+}
+
+/// collects everything written to it, in a buffer the clones share, so it can be checked after the processor
+/// that owns one of them has written to it
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+impl SharedBuffer {
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 pub fn bytes_to_16(hb: u8, lb: u8) -> u16 {
@@ -22,6 +72,7 @@ pub fn bytes_to_16(hb: u8, lb: u8) -> u16 {
 pub fn u16_to_bytes(int: u16) -> (u8, u8) {
     ((int >> 8) as u8, (int & 255) as u8)
 }
+#[allow(clippy::single_match)]
 pub fn parse_int_literal<T: Num>(s: &str) -> Result<T, String> {
     match T::from_str_radix(s, 10) {
         Ok(v) => return Ok(v),
@@ -73,6 +124,50 @@ pub fn strip_whitespace(s: &str) -> String {
     ret
 }
 
+/// reads the big endian fields of a file format, where `what` names the file in errors
+pub(crate) struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    what: &'static str
+}
+impl<'a> ByteReader<'a> {
+    pub fn new(bytes: &'a [u8], what: &'static str) -> ByteReader<'a> {
+        ByteReader { bytes, pos: 0, what }
+    }
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        match self.bytes.get(self.pos..self.pos + n) {
+            Some(b) => {
+                self.pos += n;
+                Ok(b)
+            }
+            None => Err(format!("{} is cut short", self.what))
+        }
+    }
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    /// a u32 length, then that many bytes
+    pub fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+    /// errors if there's anything left
+    pub fn finish(&self) -> Result<(), String> {
+        if self.pos != self.bytes.len() {
+            return Err(format!("{} has junk on the end", self.what))
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;